@@ -0,0 +1,95 @@
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+
+use engine::asset::AssetResult;
+use engine::core::Component;
+use engine::render::cluster::ClusterGpuBuffers;
+use engine::render::shadow::{CubeShadowMap, ShadowMap};
+use engine::render::{Material, MaterialState, ShaderProgram};
+
+use engine::engine::EngineStats;
+
+/// Per-pass scratch state threaded through `render_pass_with_material`: the
+/// currently bound program/material (so repeated surfaces using the same
+/// one skip redundant binds), the render-state diff, and the lights and
+/// shadow map `setup_light`/`setup_camera` bind onto whatever program is
+/// active for the surface being drawn.
+pub struct EngineContext {
+    pub stats: EngineStats,
+
+    /// The `ShaderProgram` most recently bound via `prepare_cache`, read
+    /// back by `setup_camera`/`setup_light` to set its uniforms.
+    pub prog: Weak<ShaderProgram>,
+    pub states: MaterialState,
+
+    pub last_material_bound: Option<Weak<Material>>,
+    pub last_light_bound: Option<Weak<ShaderProgram>>,
+
+    pub main_light: Option<Arc<Component>>,
+    pub point_lights: Vec<Arc<Component>>,
+
+    /// Directional-light shadow map for the frame being rendered, copied
+    /// from `Engine::shadow_map` by `prepare_ctx` so `setup_light` can bind
+    /// its light-space matrix and depth sampler onto whatever program is
+    /// active, instead of every program being forced to read it directly
+    /// off the engine.
+    pub shadow_map: Option<Rc<ShadowMap>>,
+
+    /// This frame's point-light cube shadow maps, copied from
+    /// `Engine::point_shadow_maps` by `prepare_ctx`, in the same order as
+    /// `point_lights`. `setup_light` binds each light's distance cubemap
+    /// alongside its fixed-array uniform; a light past the shadow maps'
+    /// length, or whose slot is `None`, simply casts no shadow.
+    pub point_shadow_maps: Vec<Option<Rc<CubeShadowMap>>>,
+
+    /// This frame's clustered-forward light assignment, uploaded by
+    /// `gather_and_sort_render_queue` before any surface of a scene pass is
+    /// drawn. `setup_light` binds it onto whatever program is active,
+    /// falling back to the fixed 4-point-light array when absent (a
+    /// render-graph fullscreen node, whose context never gathers a scene).
+    pub cluster_buffers: Option<ClusterGpuBuffers>,
+
+    pub switch_prog: u32,
+    pub switch_tex: u32,
+    pub switch_mesh: u32,
+}
+
+impl EngineContext {
+    pub fn new(stats: EngineStats) -> EngineContext {
+        EngineContext {
+            stats: stats,
+            prog: Weak::new(),
+            states: MaterialState::default(),
+            last_material_bound: None,
+            last_light_bound: None,
+            main_light: None,
+            point_lights: Vec::new(),
+            shadow_map: None,
+            point_shadow_maps: Vec::new(),
+            cluster_buffers: None,
+            switch_prog: 0,
+            switch_tex: 0,
+            switch_mesh: 0,
+        }
+    }
+
+    /// Run `bind` for `item`. No caching is done here: `bind` runs on every
+    /// call. `setup_material` skips the call when the same material is
+    /// already bound; `render_commands` calls it for every surface buffer.
+    pub fn prepare_cache<T, F>(&mut self, _item: &Rc<T>, mut bind: F) -> AssetResult<()>
+    where
+        F: FnMut(&mut Self) -> AssetResult<()>,
+    {
+        bind(self)
+    }
+
+    /// Like `prepare_cache`, but for a texture bound to a numbered unit
+    /// rather than the single active program.
+    pub fn prepare_cache_tex<T, F>(&mut self, _item: &Rc<T>, mut bind: F) -> AssetResult<()>
+    where
+        F: FnMut(&mut Self, u32) -> AssetResult<()>,
+    {
+        let unit = self.switch_tex;
+        bind(self, unit)
+    }
+}
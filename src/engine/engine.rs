@@ -1,655 +1,1279 @@
-use webgl::*;
-use na::*;
-use std::rc::{Rc, Weak};
-use std::cell::RefCell;
-use std::collections::{BTreeMap, HashMap};
-use std::sync::Arc;
-use std::ops::{Deref, DerefMut};
-
-use engine::core::{Component, ComponentBased, GameObject, SceneTree};
-use engine::render::Camera;
-use engine::render::{DepthTest, Directional, Light, Material, MaterialState, Mesh, MeshSurface,
-                     ShaderProgram};
-use engine::render::{Frustum, RenderQueue};
-use engine::asset::{AssetError, AssetResult, AssetSystem};
-use engine::context::EngineContext;
-
-use std::default::Default;
-use alga::linear::Transformation;
-
-use super::imgui;
-
-pub trait IEngine {
-    fn new_game_object(&mut self, parent: &GameObject) -> Rc<RefCell<GameObject>>;
-
-    fn asset_system<'a>(&'a self) -> &'a AssetSystem;
-
-    fn asset_system_mut<'a>(&'a mut self) -> &'a mut AssetSystem;
-
-    fn gui_context(&mut self) -> Rc<RefCell<imgui::Context>>;
-
-    fn screen_size(&self) -> (u32, u32);
-
-    fn hidpi_factor(&self) -> f32;
-}
-
-#[derive(Default, Copy, Clone)]
-pub struct EngineStats {
-    pub surfaces_count: u32,
-    pub opaque_count: u32,
-    pub transparent_count: u32,
-}
-
-pub struct Engine<A>
-where
-    A: AssetSystem,
-{
-    pub gl: WebGLRenderingContext,
-    pub objects: Vec<Weak<RefCell<GameObject>>>,
-    pub program_cache: RefCell<HashMap<&'static str, Rc<ShaderProgram>>>,
-    pub asset_system: Box<A>,
-    pub screen_size: (u32, u32),
-    pub hidpi: f32,
-    pub current_camera: RefCell<Option<Arc<Component>>>,
-
-    pub gui_context: Rc<RefCell<imgui::Context>>,
-
-    pub stats: EngineStats,
-}
-
-struct RenderCommand {
-    pub surface: Rc<MeshSurface>,
-    pub model_m: Matrix4<f32>,
-    pub cam_distance: f32,
-}
-
-#[derive(Default)]
-struct RenderQueueState {
-    states: MaterialState,
-    commands: Vec<RenderCommand>,
-}
-
-impl RenderQueueState {
-    fn sort_by_cam_distance(&mut self) {
-        self.commands.sort_unstable_by(|a, b| {
-            let adist: f32 = a.cam_distance;
-            let bdist: f32 = b.cam_distance;
-
-            bdist.partial_cmp(&adist).unwrap()
-        });
-    }
-
-    fn sort_by_cam_distance_reverse(&mut self) {
-        self.commands.sort_unstable_by(|a, b| {
-            let adist: f32 = a.cam_distance;
-            let bdist: f32 = b.cam_distance;
-
-            adist.partial_cmp(&bdist).unwrap()
-        });
-    }
-}
-
-#[derive(Default)]
-struct RenderQueueList(BTreeMap<RenderQueue, RenderQueueState>);
-
-impl RenderQueueList {
-    pub fn new() -> RenderQueueList {
-        let mut qlist = RenderQueueList::default();
-
-        // Opaque Queue
-        let state = RenderQueueState::default();
-        qlist.insert(RenderQueue::Opaque, state);
-
-        // Skybox Queue
-        let mut state = RenderQueueState::default();
-        state.states.depth_write = Some(false);
-        state.states.depth_test = Some(DepthTest::LessEqual);
-        qlist.insert(RenderQueue::Skybox, state);
-
-        // Transparent Queue
-        let mut state = RenderQueueState::default();
-        state.states.depth_write = Some(false);
-        qlist.insert(RenderQueue::Transparent, state);
-
-        // UI Queue
-        let state = RenderQueueState::default();
-        qlist.insert(RenderQueue::UI, state);
-
-        qlist
-    }
-
-    fn surface_count(&self) -> usize {
-        let mut n = 0;
-        for (_, q) in self.iter() {
-            n += q.commands.len();
-        }
-        n
-    }
-}
-
-impl Deref for RenderQueueList {
-    type Target = BTreeMap<RenderQueue, RenderQueueState>;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-impl DerefMut for RenderQueueList {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
-}
-
-fn compute_model_m(object: &GameObject) -> Matrix4<f32> {
-    object.transform.as_global_matrix()
-}
-
-pub struct ClearOption {
-    pub color: Option<(f32, f32, f32, f32)>,
-    pub clear_color: bool,
-    pub clear_depth: bool,
-    pub clear_stencil: bool,
-}
-
-impl Default for ClearOption {
-    fn default() -> Self {
-        ClearOption {
-            color: Some((0.3, 0.3, 0.3, 1.0)),
-            clear_color: true,
-            clear_depth: true,
-            clear_stencil: false,
-        }
-    }
-}
-
-fn get_max_scale(s: &Vector3<f32>) -> f32 {
-    s[0].max(s[1]).max(s[2])
-}
-
-impl<A> Engine<A>
-where
-    A: AssetSystem,
-{
-    pub fn new_scene_tree(&self) -> Rc<SceneTree> {
-        SceneTree::new()
-    }
-
-    #[cfg_attr(feature = "flame_it", flame)]
-    pub fn clear(&self, option: ClearOption) {
-        if let Some(col) = option.color {
-            self.gl.clear_color(col.0, col.1, col.2, col.3);
-        }
-
-        if option.clear_color {
-            self.gl.clear(BufferBit::Color);
-        }
-        if option.clear_depth {
-            self.gl.clear(BufferBit::Depth);
-        }
-        if option.clear_stencil {
-            self.gl.clear(BufferBit::Stencil);
-        }
-    }
-
-    pub fn resize(&mut self, size: (u32, u32)) {
-        self.screen_size = size;
-
-        self.gui_context.borrow_mut().reset();
-    }
-
-    #[cfg_attr(feature = "flame_it", flame)]
-    fn setup_material(&self, ctx: &mut EngineContext, material: &Rc<Material>) -> AssetResult<()> {
-        if let Some(ref last_material) = ctx.last_material_bound {
-            if let Some(last_material) = last_material.upgrade() {
-                if Rc::ptr_eq(&last_material, &material) {
-                    return Ok(());
-                }
-            }
-        }
-
-        ctx.prepare_cache(&material.program, |ctx| {
-            material.program.bind(&self.gl)?;
-            ctx.switch_prog += 1;
-            Ok(())
-        })?;
-
-        material.bind(|tex| {
-            ctx.prepare_cache_tex(tex, |ctx, unit| {
-                // Binding texture
-                tex.bind(&self.gl, unit)?;
-
-                ctx.switch_tex += 1;
-                Ok(())
-            })
-        })?;
-
-        self.setup_light(ctx);
-
-        ctx.last_material_bound = Some(Rc::downgrade(&material));
-
-        Ok(())
-    }
-
-    #[cfg_attr(feature = "flame_it", flame)]
-    fn setup_camera(&self, ctx: &mut EngineContext, modelm: Matrix4<f32>, camera: &Camera) {
-        let prog = ctx.prog.upgrade().unwrap();
-        // setup_camera
-        let perspective = camera.perspective(self.screen_size);
-
-        prog.set("uMVMatrix", camera.v * modelm);
-        prog.set("uPMatrix", perspective);
-
-        let skybox_v = camera.v.fixed_slice::<U3, U3>(0, 0);
-        let mut skybox_v = skybox_v.fixed_resize::<U4, U4>(0.0);
-        skybox_v.data[15] = 1.0;
-
-        prog.set("uPVMatrix", perspective * camera.v);
-        prog.set("uPVSkyboxMatrix", perspective * skybox_v);
-
-        prog.set("uNMatrix", modelm.try_inverse().unwrap().transpose());
-        prog.set("uMMatrix", modelm);
-        prog.set("uViewPos", camera.eye());
-    }
-
-    #[cfg_attr(feature = "flame_it", flame)]
-    fn setup_light(&self, ctx: &mut EngineContext) {
-        // Setup light
-        let prog = ctx.prog.upgrade().unwrap();
-
-        if let Some(ref last_prog) = ctx.last_light_bound {
-            if let Some(last_prog) = last_prog.upgrade() {
-                if Rc::ptr_eq(&prog, &last_prog) {
-                    return;
-                }
-            }
-        }
-
-        ctx.last_light_bound = Some(ctx.prog.clone());
-
-        let light_com = ctx.main_light.as_ref().unwrap();
-        let light = light_com.try_as::<Light>().unwrap();
-
-        light.borrow().bind("uDirectionalLight", &prog);
-        // So shader needs to have a vs stage light
-        light.borrow().bind("uDirectionalLightVS", &prog);
-
-        for (i, plight_com) in ctx.point_lights.iter().enumerate() {
-            let plight = plight_com.try_as::<Light>().unwrap();
-            let name = format!("uPointLights[{}]", i);
-            plight.borrow().bind(&name, &prog);
-
-            let name = format!("uPointLightsVS[{}]", i);
-            plight.borrow().bind(&name, &prog);
-        }
-    }
-
-    #[cfg_attr(feature = "flame_it", flame)]
-    fn render_commands(
-        &self,
-        ctx: &mut EngineContext,
-        q: &RenderQueueState,
-        camera: &Camera,
-        material: Option<&Rc<Material>>,
-    ) {
-        let gl = &self.gl;
-
-        for cmd in q.commands.iter() {
-            let mat = match material.as_ref() {
-                Some(&m) => &m,
-                None => &cmd.surface.material,
-            };
-
-            ctx.states.apply_defaults();
-            ctx.states.apply(&q.states);
-            ctx.states.apply(&mat.states);
-            ctx.states.commit(gl);
-
-            if let Err(err) = self.setup_material(ctx, mat) {
-                if let AssetError::NotReady = err {
-                    continue;
-                }
-
-                panic!(format!("Failed to load material, reason {:?}", err));
-            }
-
-            let prog = ctx.prog.upgrade().unwrap();
-
-            let r = ctx.prepare_cache(&cmd.surface.buffer, |ctx| {
-                cmd.surface.buffer.bind(&self.gl, &prog)?;
-                ctx.switch_mesh += 1;
-                Ok(())
-            });
-
-            match r {
-                Ok(_) => {
-                    self.setup_camera(ctx, cmd.model_m, camera);
-                    prog.commit(gl);
-                    cmd.surface.buffer.render(gl);
-                    cmd.surface.buffer.unbind(gl);
-                }
-                Err(ref err) => match *err {
-                    AssetError::NotReady => (),
-                    _ => panic!(format!("Failed to load mesh, reason {:?}", err)),
-                },
-            }
-        }
-    }
-
-    fn map_component<T, F>(&self, mut func: F)
-    where
-        T: 'static + ComponentBased,
-        F: FnMut(Arc<Component>) -> bool,
-    {
-        for obj in self.objects.iter() {
-            let result = obj.upgrade().and_then(|obj| {
-                obj.try_borrow()
-                    .ok()
-                    .and_then(|o| o.find_component::<T>().map(|(_, c)| c.clone()))
-            });
-
-            if let Some(com) = result {
-                if !func(com) {
-                    return;
-                }
-            }
-        }
-    }
-
-    fn find_all_components<T>(&self) -> Vec<Arc<Component>>
-    where
-        T: 'static + ComponentBased,
-    {
-        let mut result = Vec::new();
-        self.map_component::<T, _>(|c| {
-            result.push(c);
-            true
-        });
-
-        result
-    }
-
-    pub fn find_component<T>(&self) -> Option<Arc<Component>>
-    where
-        T: 'static + ComponentBased,
-    {
-        let mut r = None;
-        self.map_component::<T, _>(|c| {
-            r = Some(c);
-            false
-        });
-
-        r
-    }
-
-    pub fn find_main_light(&self) -> Option<Arc<Component>> {
-        self.find_all_components::<Light>()
-            .into_iter()
-            .filter(|c| {
-                let light_com = c.try_as::<Light>().unwrap();
-                match *light_com.borrow() {
-                    Light::Directional(_) => true,
-                    _ => false,
-                }
-            })
-            .nth(0)
-    }
-
-    fn prepare_ctx(&self, ctx: &mut EngineContext) {
-        // prepare main light.
-        ctx.main_light = Some(
-            self.find_main_light()
-                .unwrap_or({ Component::new(Light::new(Directional::default())) }),
-        );
-
-        ctx.point_lights = self.find_all_components::<Light>()
-                .into_iter()
-                .filter(|c| {
-                    let light_com = c.try_as::<Light>().unwrap();
-                    match *light_com.borrow() {
-                        Light::Point(_) => true,
-                        _ => false,
-                    }
-                })
-                .take(4)            // only take 4 points light.
-                .map(
-                    |c| c.clone()
-                )
-                .collect();
-    }
-
-    fn gather_render_commands(
-        &self,
-        object: &GameObject,
-        cam_pos: &Vector3<f32>,
-        frustum: &Frustum,
-        render_q: &mut RenderQueueList,
-    ) {
-        if !object.active {
-            return;
-        }
-
-        let result = object.find_component::<Mesh>();
-
-        if let Some((mesh, _)) = result {
-            for surface in mesh.surfaces.iter() {
-                let m = compute_model_m(&*object);
-
-                match surface.material.render_queue {
-                    RenderQueue::Skybox | RenderQueue::UI => (),
-                    _ => {
-                        let bounds = surface.buffer.bounds();
-                        if bounds.is_none() {
-                            continue;
-                        }
-
-                        let p = m.transform_point(&Point3::new(0.0, 0.0, 0.0));
-                        let scale = get_max_scale(&object.transform.local_scale());
-                        let scaled_r = bounds.unwrap().r * scale;
-
-                        if !frustum.collide_sphere(&p.coords, scaled_r) {
-                            continue;
-                        }
-                    }
-                }
-
-                let q = render_q.get_mut(&surface.material.render_queue).unwrap();
-
-                let cam_dist =
-                    (cam_pos - object.transform.global().translation.vector).norm_squared();
-
-                q.commands.push(RenderCommand {
-                    surface: surface.clone(),
-                    model_m: m,
-                    cam_distance: cam_dist,
-                })
-            }
-        }
-    }
-
-    #[cfg_attr(feature = "flame_it", flame)]
-    pub fn render_pass_with_material(
-        &mut self,
-        camera: &Camera,
-        material: Option<&Rc<Material>>,
-        clear_option: ClearOption,
-    ) {
-        let objects = &self.objects;
-
-        let mut ctx: EngineContext = EngineContext::new(self.stats);
-
-        if let Some(ref rt) = camera.render_texture {
-            rt.bind_frame_buffer(&self.gl);
-        }
-
-        match camera.rect {
-            Some(((x, y), (w, h))) => {
-                self.gl.viewport(x, y, w, h);
-            }
-            None => {
-                self.gl
-                    .viewport(0, 0, self.screen_size.0, self.screen_size.1);
-            }
-        }
-
-        self.clear(clear_option);
-
-        self.prepare_ctx(&mut ctx);
-
-        let mut render_q = RenderQueueList::new();
-
-        let frustum = camera.calc_frustum(self.screen_size);
-
-        // gather commands
-        for obj in objects.iter() {
-            obj.upgrade().map(|obj| {
-                if let Ok(object) = obj.try_borrow() {
-                    self.gather_render_commands(&object, &camera.eye(), &frustum, &mut render_q)
-                }
-            });
-        }
-
-        // Sort the opaque queue
-        render_q
-            .get_mut(&RenderQueue::Opaque)
-            .unwrap()
-            .sort_by_cam_distance_reverse();
-
-        // Sort the transparent queue
-        render_q
-            .get_mut(&RenderQueue::Transparent)
-            .unwrap()
-            .sort_by_cam_distance();
-
-        ctx.stats.surfaces_count = render_q.surface_count() as u32;
-        ctx.stats.transparent_count = render_q
-            .get(&RenderQueue::Transparent)
-            .unwrap()
-            .commands
-            .len() as u32;
-        ctx.stats.opaque_count = render_q.get(&RenderQueue::Opaque).unwrap().commands.len() as u32;
-
-        for (_, q) in render_q.iter() {
-            self.render_commands(&mut ctx, &q, camera, material);
-        }
-
-        if let Some(ref rt) = camera.render_texture {
-            rt.unbind_frame_buffer(&self.gl);
-        }
-
-        self.stats = ctx.stats;
-    }
-
-    #[cfg_attr(feature = "flame_it", flame)]
-    pub fn render_pass(&mut self, camera: &Camera, clear_option: ClearOption) {
-        self.render_pass_with_material(camera, None, clear_option);
-    }
-
-    pub fn main_camera(&self) -> Option<Arc<Component>> {
-        let mut found = self.current_camera.borrow_mut();
-        match *found {
-            None => *found = self.find_component::<Camera>().map(|c| c.clone()),
-            _ => (),
-        }
-
-        if let Some(ref c) = *found {
-            return Some(c.clone());
-        }
-
-        None
-    }
-
-    #[cfg_attr(feature = "flame_it", flame)]
-    pub fn render(&mut self, clear_option: ClearOption) {
-        imgui::pre_render(self);
-
-        if let Some(ref camera) = self.main_camera() {
-            self.render_pass(&camera.try_as::<Camera>().unwrap().borrow(), clear_option);
-        } else {
-            // We dont have a main camera here, just clean the screen.
-            self.clear(clear_option);
-        }
-    }
-
-    pub fn new(webgl_ctx: WebGLContext, size: (u32, u32), hidpi: f32) -> Engine<A> {
-        let gl = WebGLRenderingContext::new(webgl_ctx);
-
-        /*=========Drawing the triangle===========*/
-
-        // Clear the canvas
-        gl.clear_color(0.5, 0.5, 0.5, 1.0);
-
-        // Enable alpha blending
-        gl.enable(Flag::Blend as i32);
-
-        // Clear the color buffer bit
-        gl.clear(BufferBit::Color);
-        gl.clear(BufferBit::Depth);
-        gl.blend_func(BlendMode::SrcAlpha, BlendMode::OneMinusSrcAlpha);
-
-        // Set the view port
-        gl.viewport(0, 0, size.0, size.1);
-
-        let gui_tree = SceneTree::new();
-
-        Engine {
-            gl: gl,
-            objects: vec![],
-            program_cache: RefCell::new(HashMap::new()),
-            asset_system: Box::new(A::new()),
-            gui_context: Rc::new(RefCell::new(imgui::Context::new(gui_tree))),
-            screen_size: size,
-            hidpi: hidpi,
-            current_camera: RefCell::new(None),
-            stats: Default::default(),
-        }
-    }
-
-    pub fn begin(&mut self) {
-        imgui::begin();
-
-        self.asset_system_mut().step();
-    }
-
-    pub fn end(&mut self) {
-        // drop all gameobjects if there are no other references
-        self.objects.retain(|obj| obj.upgrade().is_some());
-
-        // drop camera cache if it is only by holded by ourself
-        let mut cam_mut = self.current_camera.borrow_mut();
-        if let Some(ref c) = *cam_mut {
-            if Arc::strong_count(&c) == 1 {
-                cam_mut.take();
-            }
-        }
-    }
-}
-
-impl<A: AssetSystem> IEngine for Engine<A> {
-    fn new_game_object(&mut self, parent: &GameObject) -> Rc<RefCell<GameObject>> {
-        let go = parent.tree().new_node(parent);
-
-        self.objects.push(Rc::downgrade(&go));
-        go
-    }
-
-    fn gui_context(&mut self) -> Rc<RefCell<imgui::Context>> {
-        self.gui_context.clone()
-    }
-
-    fn asset_system<'a>(&'a self) -> &'a AssetSystem {
-        &*self.asset_system
-    }
-
-    fn asset_system_mut<'a>(&'a mut self) -> &'a mut AssetSystem {
-        &mut *self.asset_system
-    }
-
-    fn screen_size(&self) -> (u32, u32) {
-        self.screen_size
-    }
-
-    fn hidpi_factor(&self) -> f32 {
-        self.hidpi
-    }
-}
+use webgl::*;
+use na::*;
+use std::rc::{Rc, Weak};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::ops::{Deref, DerefMut};
+
+use engine::core::{Component, ComponentBased, GameObject, SceneTree};
+use engine::render::Camera;
+use engine::render::{DepthTest, Directional, Light, Material, MaterialState, Mesh, MeshSurface,
+                     ShaderProgram};
+use engine::render::{Frustum, RenderQueue, RenderTexture};
+use engine::render::shadow::{CubeShadowMap, ShadowFilterMode, ShadowMap};
+use engine::render::camera_binding::CameraBinding;
+use engine::render::cluster::{ClusterGpuBuffers, ClusterState, CLUSTER_X, CLUSTER_Y, CLUSTER_Z};
+use engine::render::hiz::{self, DepthPyramid};
+use engine::asset::{AssetError, AssetResult, AssetSystem};
+use engine::context::EngineContext;
+use engine::shader_preprocessor::{self, Define, ShaderVariantKey};
+use engine::render_graph::{RenderGraph, RenderGraphNode};
+
+use std::default::Default;
+use alga::linear::Transformation;
+
+use super::imgui;
+
+pub trait IEngine {
+    fn new_game_object(&mut self, parent: &GameObject) -> Rc<RefCell<GameObject>>;
+
+    fn asset_system<'a>(&'a self) -> &'a AssetSystem;
+
+    fn asset_system_mut<'a>(&'a mut self) -> &'a mut AssetSystem;
+
+    fn gui_context(&mut self) -> Rc<RefCell<imgui::Context>>;
+
+    fn screen_size(&self) -> (u32, u32);
+
+    fn hidpi_factor(&self) -> f32;
+}
+
+/// How many point lights get their own cube shadow map, matching the
+/// fixed-array point-light fallback `setup_light` already uses when no
+/// clustered light buffers are bound.
+const MAX_POINT_SHADOWS: usize = 4;
+
+#[derive(Default, Copy, Clone)]
+pub struct EngineStats {
+    pub surfaces_count: u32,
+    pub opaque_count: u32,
+    pub transparent_count: u32,
+}
+
+pub struct Engine<A>
+where
+    A: AssetSystem,
+{
+    pub gl: WebGLRenderingContext,
+    pub objects: Vec<Weak<RefCell<GameObject>>>,
+    pub program_cache: RefCell<HashMap<ShaderVariantKey, Rc<ShaderProgram>>>,
+    pub asset_system: Box<A>,
+    pub screen_size: (u32, u32),
+    pub hidpi: f32,
+    pub current_camera: RefCell<Option<Arc<Component>>>,
+
+    pub gui_context: Rc<RefCell<imgui::Context>>,
+
+    pub stats: EngineStats,
+
+    /// Directional-light shadow map, rebuilt by `render_shadow_pass` whenever
+    /// the main light's `ShadowSettings` change.
+    shadow_map: RefCell<Option<Rc<ShadowMap>>>,
+
+    /// Per-point-light cube shadow maps, rebuilt by `render_point_shadow_pass`
+    /// each frame. Indexed in the same order `find_all_components::<Light>`
+    /// returns point lights in, capped at `MAX_POINT_SHADOWS` like the
+    /// fixed-array point-light fallback in `setup_light`; a light with no
+    /// `ShadowSettings` keeps its slot as `None`.
+    point_shadow_maps: RefCell<Vec<Option<Rc<CubeShadowMap>>>>,
+
+    /// Per-frame clustered-forward light assignment, rebuilt every
+    /// `render_pass_with_material` call.
+    cluster_state: RefCell<ClusterState>,
+
+    /// Hi-Z depth mip pyramid used to occlusion-cull surfaces in
+    /// `gather_render_commands`, rebuilt each frame `build_hiz_pyramid` runs.
+    depth_pyramid: RefCell<Option<DepthPyramid>>,
+
+    /// User-declared multi-pass render graph. When set, `render` walks it
+    /// in topological order instead of issuing the single default
+    /// `render_pass`, enabling post-processing chains (bloom, FXAA, tone
+    /// mapping, ...) built from `RenderGraphNode`s.
+    pub render_graph: Option<RenderGraph>,
+}
+
+struct RenderCommand {
+    pub surface: Rc<MeshSurface>,
+    pub model_m: Matrix4<f32>,
+    pub cam_distance: f32,
+}
+
+#[derive(Default)]
+struct RenderQueueState {
+    states: MaterialState,
+    commands: Vec<RenderCommand>,
+}
+
+impl RenderQueueState {
+    fn sort_by_cam_distance(&mut self) {
+        self.commands.sort_unstable_by(|a, b| {
+            let adist: f32 = a.cam_distance;
+            let bdist: f32 = b.cam_distance;
+
+            bdist.partial_cmp(&adist).unwrap()
+        });
+    }
+
+    fn sort_by_cam_distance_reverse(&mut self) {
+        self.commands.sort_unstable_by(|a, b| {
+            let adist: f32 = a.cam_distance;
+            let bdist: f32 = b.cam_distance;
+
+            adist.partial_cmp(&bdist).unwrap()
+        });
+    }
+}
+
+#[derive(Default)]
+struct RenderQueueList(BTreeMap<RenderQueue, RenderQueueState>);
+
+impl RenderQueueList {
+    pub fn new() -> RenderQueueList {
+        let mut qlist = RenderQueueList::default();
+
+        // Opaque Queue
+        let state = RenderQueueState::default();
+        qlist.insert(RenderQueue::Opaque, state);
+
+        // Skybox Queue
+        let mut state = RenderQueueState::default();
+        state.states.depth_write = Some(false);
+        state.states.depth_test = Some(DepthTest::LessEqual);
+        qlist.insert(RenderQueue::Skybox, state);
+
+        // Transparent Queue
+        let mut state = RenderQueueState::default();
+        state.states.depth_write = Some(false);
+        qlist.insert(RenderQueue::Transparent, state);
+
+        // UI Queue
+        let state = RenderQueueState::default();
+        qlist.insert(RenderQueue::UI, state);
+
+        qlist
+    }
+
+    fn surface_count(&self) -> usize {
+        let mut n = 0;
+        for (_, q) in self.iter() {
+            n += q.commands.len();
+        }
+        n
+    }
+}
+
+impl Deref for RenderQueueList {
+    type Target = BTreeMap<RenderQueue, RenderQueueState>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for RenderQueueList {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+fn compute_model_m(object: &GameObject) -> Matrix4<f32> {
+    object.transform.as_global_matrix()
+}
+
+#[derive(Copy, Clone)]
+pub struct ClearOption {
+    pub color: Option<(f32, f32, f32, f32)>,
+    pub clear_color: bool,
+    pub clear_depth: bool,
+    pub clear_stencil: bool,
+
+    /// Enable Hi-Z occlusion culling in `gather_render_commands`. Only ever
+    /// applies to the opaque queue; skybox/UI skip frustum culling, and
+    /// transparent is frustum culled, but none of them are Hi-Z culled.
+    pub occlusion_culling: bool,
+}
+
+impl Default for ClearOption {
+    fn default() -> Self {
+        ClearOption {
+            color: Some((0.3, 0.3, 0.3, 1.0)),
+            clear_color: true,
+            clear_depth: true,
+            clear_stencil: false,
+            occlusion_culling: false,
+        }
+    }
+}
+
+fn get_max_scale(s: &Vector3<f32>) -> f32 {
+    s[0].max(s[1]).max(s[2])
+}
+
+impl<A> Engine<A>
+where
+    A: AssetSystem,
+{
+    pub fn new_scene_tree(&self) -> Rc<SceneTree> {
+        SceneTree::new()
+    }
+
+    #[cfg_attr(feature = "flame_it", flame)]
+    pub fn clear(&self, option: ClearOption) {
+        if let Some(col) = option.color {
+            self.gl.clear_color(col.0, col.1, col.2, col.3);
+        }
+
+        if option.clear_color {
+            self.gl.clear(BufferBit::Color);
+        }
+        if option.clear_depth {
+            self.gl.clear(BufferBit::Depth);
+        }
+        if option.clear_stencil {
+            self.gl.clear(BufferBit::Stencil);
+        }
+    }
+
+    pub fn resize(&mut self, size: (u32, u32)) {
+        self.screen_size = size;
+
+        self.gui_context.borrow_mut().reset();
+    }
+
+    /// Resolve `#include`/`#define`/`#ifdef` against `path`, then compile and
+    /// cache the resulting variant keyed on (path, sorted defines) so e.g.
+    /// `SHADOWS` and non-`SHADOWS` builds of the same source coexist.
+    pub fn get_or_compile_program(
+        &self,
+        path: &str,
+        defines: &[Define],
+    ) -> AssetResult<Rc<ShaderProgram>> {
+        let key = ShaderVariantKey::new(path, defines);
+
+        if let Some(prog) = self.program_cache.borrow().get(&key) {
+            return Ok(prog.clone());
+        }
+
+        let source = shader_preprocessor::preprocess(&*self.asset_system, path, defines)?;
+        let prog = Rc::new(ShaderProgram::compile(&self.gl, &source));
+
+        self.program_cache
+            .borrow_mut()
+            .insert(key, prog.clone());
+
+        Ok(prog)
+    }
+
+    #[cfg_attr(feature = "flame_it", flame)]
+    fn setup_material(&self, ctx: &mut EngineContext, material: &Rc<Material>) -> AssetResult<()> {
+        if let Some(ref last_material) = ctx.last_material_bound {
+            if let Some(last_material) = last_material.upgrade() {
+                if Rc::ptr_eq(&last_material, &material) {
+                    return Ok(());
+                }
+            }
+        }
+
+        ctx.prepare_cache(&material.program, |ctx| {
+            material.program.bind(&self.gl)?;
+            ctx.switch_prog += 1;
+            Ok(())
+        })?;
+        ctx.prog = Rc::downgrade(&material.program);
+
+        material.bind(|tex| {
+            ctx.prepare_cache_tex(tex, |ctx, unit| {
+                // Binding texture
+                tex.bind(&self.gl, unit)?;
+
+                ctx.switch_tex += 1;
+                Ok(())
+            })
+        })?;
+
+        self.setup_light(ctx);
+
+        ctx.last_material_bound = Some(Rc::downgrade(&material));
+
+        Ok(())
+    }
+
+    /// Only compute and upload the camera matrices `prog` actually declares
+    /// (per its `camera_bindings`), rather than the full fixed set — a
+    /// skybox shader that only declares `uPVSkyboxMatrix` never pays for
+    /// `uMVMatrix`/`uNMatrix`/etc. it never reads.
+    #[cfg_attr(feature = "flame_it", flame)]
+    fn setup_camera(&self, ctx: &mut EngineContext, modelm: Matrix4<f32>, camera: &Camera) {
+        let prog = ctx.prog.upgrade().unwrap();
+
+        prog.set("uMMatrix", modelm);
+
+        let perspective = camera.perspective(self.screen_size);
+
+        for binding in prog.camera_bindings() {
+            match *binding {
+                CameraBinding::View => prog.set(binding.uniform_name(), camera.v * modelm),
+                CameraBinding::Proj => prog.set(binding.uniform_name(), perspective),
+                CameraBinding::ViewProj => {
+                    prog.set(binding.uniform_name(), perspective * camera.v)
+                }
+                CameraBinding::ViewPos => prog.set(binding.uniform_name(), camera.eye()),
+                CameraBinding::Normal => prog.set(
+                    binding.uniform_name(),
+                    modelm.try_inverse().unwrap().transpose(),
+                ),
+            }
+        }
+
+        // The skybox's PV matrix strips the view matrix's translation so
+        // the skybox never appears to move with the camera; it isn't one
+        // of the named `CameraBinding`s since no other shader wants it.
+        if prog.declares_uniform("uPVSkyboxMatrix") {
+            let skybox_v = camera.v.fixed_slice::<U3, U3>(0, 0);
+            let mut skybox_v = skybox_v.fixed_resize::<U4, U4>(0.0);
+            skybox_v.data[15] = 1.0;
+
+            prog.set("uPVSkyboxMatrix", perspective * skybox_v);
+        }
+    }
+
+    #[cfg_attr(feature = "flame_it", flame)]
+    fn setup_light(&self, ctx: &mut EngineContext) {
+        // Setup light
+        let prog = ctx.prog.upgrade().unwrap();
+
+        if let Some(ref last_prog) = ctx.last_light_bound {
+            if let Some(last_prog) = last_prog.upgrade() {
+                if Rc::ptr_eq(&prog, &last_prog) {
+                    return;
+                }
+            }
+        }
+
+        ctx.last_light_bound = Some(ctx.prog.clone());
+
+        let light_com = ctx.main_light.as_ref().unwrap();
+        let light = light_com.try_as::<Light>().unwrap();
+
+        light.borrow().bind("uDirectionalLight", &prog);
+        // So shader needs to have a vs stage light
+        light.borrow().bind("uDirectionalLightVS", &prog);
+
+        if let Some(ref shadow_map) = ctx.shadow_map {
+            prog.set("uLightSpaceMatrix", shadow_map.light_space_matrix);
+            prog.set("uShadowBias", shadow_map.settings.depth_bias);
+            prog.set("uShadowKernelRadius", shadow_map.settings.kernel_radius);
+            prog.set("uShadowLightSize", shadow_map.settings.light_size);
+            prog.set(
+                "uShadowFilterMode",
+                match shadow_map.settings.filter {
+                    ShadowFilterMode::None => 0,
+                    ShadowFilterMode::Hardware2x2 => 1,
+                    ShadowFilterMode::Pcf => 2,
+                    ShadowFilterMode::Pcss => 3,
+                },
+            );
+            shadow_map.depth_texture.bind_tex(&prog, "uShadowMap");
+        }
+
+        match ctx.cluster_buffers {
+            Some(ref buffers) => {
+                buffers.offset_tex.bind_tex(&prog, "uClusterOffsets");
+                buffers.index_tex.bind_tex(&prog, "uClusterLightIndices");
+                buffers.light_data_tex.bind_tex(&prog, "uClusterLightData");
+
+                prog.set(
+                    "uClusterGrid",
+                    Vector3::new(CLUSTER_X as f32, CLUSTER_Y as f32, CLUSTER_Z as f32),
+                );
+                prog.set(
+                    "uScreenSize",
+                    Vector2::new(self.screen_size.0 as f32, self.screen_size.1 as f32),
+                );
+            }
+            // Fixed 4-slot fallback for programs drawn without cluster
+            // buffers (render-graph fullscreen nodes).
+            None => {
+                for (i, plight_com) in ctx.point_lights.iter().take(MAX_POINT_SHADOWS).enumerate() {
+                    let plight = plight_com.try_as::<Light>().unwrap();
+                    let name = format!("uPointLights[{}]", i);
+                    plight.borrow().bind(&name, &prog);
+
+                    let name = format!("uPointLightsVS[{}]", i);
+                    plight.borrow().bind(&name, &prog);
+
+                    // Lights past `point_shadow_maps.len()` (cap
+                    // MAX_POINT_SHADOWS) or with a `None` slot (no
+                    // ShadowSettings) cast no shadow; the shader falls
+                    // back to fully lit.
+                    if let Some(&Some(ref cube)) = ctx.point_shadow_maps.get(i) {
+                        prog.set(&format!("uPointShadowFarPlane[{}]", i), cube.far_plane);
+                        for face in 0..6 {
+                            cube.faces[face]
+                                .bind_tex(&prog, &format!("uPointShadowMap[{}][{}]", i, face));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg_attr(feature = "flame_it", flame)]
+    fn render_commands(
+        &self,
+        ctx: &mut EngineContext,
+        q: &RenderQueueState,
+        camera: &Camera,
+        material: Option<&Rc<Material>>,
+    ) {
+        let gl = &self.gl;
+
+        for cmd in q.commands.iter() {
+            let mat = match material.as_ref() {
+                Some(&m) => &m,
+                None => &cmd.surface.material,
+            };
+
+            ctx.states.apply_defaults();
+            ctx.states.apply(&q.states);
+            ctx.states.apply(&mat.states);
+            ctx.states.commit(gl);
+
+            if let Err(err) = self.setup_material(ctx, mat) {
+                if let AssetError::NotReady = err {
+                    continue;
+                }
+
+                panic!(format!("Failed to load material, reason {:?}", err));
+            }
+
+            let prog = ctx.prog.upgrade().unwrap();
+
+            let r = ctx.prepare_cache(&cmd.surface.buffer, |ctx| {
+                cmd.surface.buffer.bind(&self.gl, &prog)?;
+                ctx.switch_mesh += 1;
+                Ok(())
+            });
+
+            match r {
+                Ok(_) => {
+                    self.setup_camera(ctx, cmd.model_m, camera);
+                    prog.commit(gl);
+                    cmd.surface.buffer.render(gl);
+                    cmd.surface.buffer.unbind(gl);
+                }
+                Err(ref err) => match *err {
+                    AssetError::NotReady => (),
+                    _ => panic!(format!("Failed to load mesh, reason {:?}", err)),
+                },
+            }
+        }
+    }
+
+    fn map_component<T, F>(&self, mut func: F)
+    where
+        T: 'static + ComponentBased,
+        F: FnMut(Arc<Component>) -> bool,
+    {
+        for obj in self.objects.iter() {
+            let result = obj.upgrade().and_then(|obj| {
+                obj.try_borrow()
+                    .ok()
+                    .and_then(|o| o.find_component::<T>().map(|(_, c)| c.clone()))
+            });
+
+            if let Some(com) = result {
+                if !func(com) {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn find_all_components<T>(&self) -> Vec<Arc<Component>>
+    where
+        T: 'static + ComponentBased,
+    {
+        let mut result = Vec::new();
+        self.map_component::<T, _>(|c| {
+            result.push(c);
+            true
+        });
+
+        result
+    }
+
+    pub fn find_component<T>(&self) -> Option<Arc<Component>>
+    where
+        T: 'static + ComponentBased,
+    {
+        let mut r = None;
+        self.map_component::<T, _>(|c| {
+            r = Some(c);
+            false
+        });
+
+        r
+    }
+
+    pub fn find_main_light(&self) -> Option<Arc<Component>> {
+        self.find_all_components::<Light>()
+            .into_iter()
+            .filter(|c| {
+                let light_com = c.try_as::<Light>().unwrap();
+                match *light_com.borrow() {
+                    Light::Directional(_) => true,
+                    _ => false,
+                }
+            })
+            .nth(0)
+    }
+
+    fn prepare_ctx(&self, ctx: &mut EngineContext) {
+        // prepare main light.
+        ctx.main_light = Some(
+            self.find_main_light()
+                .unwrap_or({ Component::new(Light::new(Directional::default())) }),
+        );
+
+        ctx.shadow_map = self.shadow_map.borrow().clone();
+        ctx.point_shadow_maps = self.point_shadow_maps.borrow().clone();
+
+        // All point lights are kept here: the clustered path assigns every
+        // one of them to clusters, while the fixed-array fallback in
+        // `setup_light` still only binds the first 4.
+        ctx.point_lights = self.find_all_components::<Light>()
+                .into_iter()
+                .filter(|c| {
+                    let light_com = c.try_as::<Light>().unwrap();
+                    match *light_com.borrow() {
+                        Light::Point(_) => true,
+                        _ => false,
+                    }
+                })
+                .map(
+                    |c| c.clone()
+                )
+                .collect();
+    }
+
+    fn gather_render_commands(
+        &self,
+        object: &GameObject,
+        cam_pos: &Vector3<f32>,
+        frustum: &Frustum,
+        camera: &Camera,
+        hiz: Option<&DepthPyramid>,
+        render_q: &mut RenderQueueList,
+    ) {
+        if !object.active {
+            return;
+        }
+
+        let result = object.find_component::<Mesh>();
+
+        if let Some((mesh, _)) = result {
+            for surface in mesh.surfaces.iter() {
+                let m = compute_model_m(&*object);
+
+                match surface.material.render_queue {
+                    RenderQueue::Skybox | RenderQueue::UI => (),
+                    _ => {
+                        let bounds = surface.buffer.bounds();
+                        if bounds.is_none() {
+                            continue;
+                        }
+
+                        let p = m.transform_point(&Point3::new(0.0, 0.0, 0.0));
+                        let scale = get_max_scale(&object.transform.local_scale());
+                        let scaled_r = bounds.unwrap().r * scale;
+
+                        if !frustum.collide_sphere(&p.coords, scaled_r) {
+                            continue;
+                        }
+
+                        // Opaque occluder depth is all the pyramid was ever
+                        // built from, so a translucent surface behind one
+                        // isn't necessarily hidden by it: Transparent is
+                        // frustum culled above like everything else, but
+                        // never Hi-Z occlusion culled.
+                        if let (Some(pyramid), false) =
+                            (hiz, surface.material.render_queue == RenderQueue::Transparent)
+                        {
+                            let aabb = hiz::project_bounding_sphere(
+                                camera,
+                                self.screen_size,
+                                &p,
+                                scaled_r,
+                            );
+                            let mip = pyramid.mip_for_screen_aabb(aabb);
+
+                            // Closest point of the bounding sphere to the
+                            // camera, projected into the pyramid's device-depth
+                            // space so it can be compared against the stored
+                            // (also device-depth) occluder depth directly.
+                            let to_cam = cam_pos - p.coords;
+                            let nearest_point = if to_cam.norm() > scaled_r {
+                                p + to_cam.normalize() * scaled_r
+                            } else {
+                                p
+                            };
+                            let nearest_depth = hiz::device_depth(camera, self.screen_size, &nearest_point);
+
+                            if hiz::is_occluded(nearest_depth, pyramid.sample_depth(mip, aabb)) {
+                                continue;
+                            }
+                        }
+                    }
+                }
+
+                let q = render_q.get_mut(&surface.material.render_queue).unwrap();
+
+                let cam_dist =
+                    (cam_pos - object.transform.global().translation.vector).norm_squared();
+
+                q.commands.push(RenderCommand {
+                    surface: surface.clone(),
+                    model_m: m,
+                    cam_distance: cam_dist,
+                })
+            }
+        }
+    }
+
+    /// Render scene depth from the main directional light's point of view
+    /// into `self.shadow_map`, fit to the given camera's frustum. When the
+    /// main light has no `ShadowSettings` (shadows disabled), drops any
+    /// previous map and renders nothing.
+    #[cfg_attr(feature = "flame_it", flame)]
+    fn render_shadow_pass(&mut self, camera: &Camera) {
+        let light_com = match self.find_main_light() {
+            Some(l) => l,
+            None => return,
+        };
+        let light = light_com.try_as::<Light>().unwrap();
+
+        let settings = match light.borrow().shadow_settings() {
+            Some(s) => s,
+            None => {
+                self.shadow_map.borrow_mut().take();
+                return;
+            }
+        };
+
+        let needs_new = match *self.shadow_map.borrow() {
+            Some(ref sm) => sm.settings.resolution != settings.resolution,
+            None => true,
+        };
+
+        if needs_new {
+            *self.shadow_map.borrow_mut() = Some(Rc::new(ShadowMap::new(&self.gl, settings)));
+        }
+
+        let frustum = camera.calc_frustum(self.screen_size);
+        let light_dir = light.borrow().direction();
+
+        {
+            let mut map_ref = self.shadow_map.borrow_mut();
+            let shadow_map =
+                Rc::get_mut(map_ref.as_mut().unwrap()).expect("shadow map shared while rendering");
+            shadow_map.settings = settings;
+            shadow_map.fit_to_frustum(&light_dir, camera, &frustum);
+        }
+
+        let shadow_map = self.shadow_map.borrow().clone().unwrap();
+
+        shadow_map.depth_texture.bind_frame_buffer(&self.gl);
+        self.gl.viewport(
+            0,
+            0,
+            shadow_map.settings.resolution as i32,
+            shadow_map.settings.resolution as i32,
+        );
+        self.gl.clear(BufferBit::Depth);
+
+        let depth_prog = match self.get_or_compile_program("shaders/depth_only.glsl", &[]) {
+            Ok(p) => p,
+            Err(AssetError::NotReady) => return,
+            Err(err) => panic!(format!("Failed to compile depth-only shader, reason {:?}", err)),
+        };
+
+        for obj in self.objects.iter() {
+            obj.upgrade().map(|obj| {
+                let object = match obj.try_borrow() {
+                    Ok(object) => object,
+                    Err(_) => return,
+                };
+
+                if !object.active {
+                    return;
+                }
+
+                if let Some((mesh, _)) = object.find_component::<Mesh>() {
+                    for surface in mesh.surfaces.iter() {
+                        // Only opaque casters fight shadow acne meaningfully;
+                        // skybox/UI/transparent never write into the depth map.
+                        if surface.material.render_queue != RenderQueue::Opaque {
+                            continue;
+                        }
+
+                        let m = compute_model_m(&*object);
+
+                        if depth_prog.bind(&self.gl).is_err() {
+                            continue;
+                        }
+
+                        depth_prog.set("uLightSpaceMatrix", shadow_map.light_space_matrix);
+                        depth_prog.set("uMMatrix", m);
+                        depth_prog.set("uShadowBias", shadow_map.settings.depth_bias);
+                        depth_prog.commit(&self.gl);
+
+                        if surface.buffer.bind(&self.gl, &depth_prog).is_ok() {
+                            surface.buffer.render(&self.gl);
+                            surface.buffer.unbind(&self.gl);
+                        }
+                    }
+                }
+            });
+        }
+
+        shadow_map.depth_texture.unbind_frame_buffer(&self.gl);
+    }
+
+    /// Render each point light's cube shadow map: 6 faces, one per cube
+    /// direction, each storing *linear distance to the light* (not a
+    /// projected face depth) so the shading pass can compare every face the
+    /// same way regardless of which one a fragment falls behind. Lights
+    /// beyond `MAX_POINT_SHADOWS`, or with no `ShadowSettings`, cast no
+    /// shadow, same as the directional light's `render_shadow_pass`.
+    #[cfg_attr(feature = "flame_it", flame)]
+    fn render_point_shadow_pass(&mut self) {
+        let point_lights: Vec<_> = self.find_all_components::<Light>()
+            .into_iter()
+            .filter(|c| {
+                let light_com = c.try_as::<Light>().unwrap();
+                match *light_com.borrow() {
+                    Light::Point(_) => true,
+                    _ => false,
+                }
+            })
+            .take(MAX_POINT_SHADOWS)
+            .collect();
+
+        let depth_prog = match self.get_or_compile_program("shaders/point_shadow_distance.glsl", &[]) {
+            Ok(p) => p,
+            Err(AssetError::NotReady) => return,
+            Err(err) => panic!(format!(
+                "Failed to compile point shadow distance shader, reason {:?}",
+                err
+            )),
+        };
+
+        let mut maps = Vec::with_capacity(point_lights.len());
+
+        for light_com in point_lights.iter() {
+            let light = light_com.try_as::<Light>().unwrap();
+            let light = light.borrow();
+
+            let settings = match light.shadow_settings() {
+                Some(s) => s,
+                None => {
+                    maps.push(None);
+                    continue;
+                }
+            };
+
+            let mut cube = CubeShadowMap::new(&self.gl, settings);
+            cube.light_pos = light.position();
+            cube.far_plane = light.radius();
+
+            for face in 0..6 {
+                cube.faces[face].bind_frame_buffer(&self.gl);
+                self.gl
+                    .viewport(0, 0, settings.resolution as i32, settings.resolution as i32);
+                self.gl.clear(BufferBit::Depth);
+
+                let view_proj = cube.face_view_proj(face);
+
+                for obj in self.objects.iter() {
+                    obj.upgrade().map(|obj| {
+                        let object = match obj.try_borrow() {
+                            Ok(object) => object,
+                            Err(_) => return,
+                        };
+
+                        if !object.active {
+                            return;
+                        }
+
+                        if let Some((mesh, _)) = object.find_component::<Mesh>() {
+                            for surface in mesh.surfaces.iter() {
+                                if surface.material.render_queue != RenderQueue::Opaque {
+                                    continue;
+                                }
+
+                                let m = compute_model_m(&*object);
+
+                                if depth_prog.bind(&self.gl).is_err() {
+                                    continue;
+                                }
+
+                                depth_prog.set("uMVPMatrix", view_proj * m);
+                                depth_prog.set("uMMatrix", m);
+                                depth_prog.set("uLightPos", cube.light_pos);
+                                depth_prog.set("uFarPlane", cube.far_plane);
+                                depth_prog.commit(&self.gl);
+
+                                if surface.buffer.bind(&self.gl, &depth_prog).is_ok() {
+                                    surface.buffer.render(&self.gl);
+                                    surface.buffer.unbind(&self.gl);
+                                }
+                            }
+                        }
+                    });
+                }
+
+                cube.faces[face].unbind_frame_buffer(&self.gl);
+            }
+
+            maps.push(Some(Rc::new(cube)));
+        }
+
+        *self.point_shadow_maps.borrow_mut() = maps;
+    }
+
+    /// Render an early depth prepass of large opaque occluders, then
+    /// downsample it into `self.depth_pyramid` by taking the max (farthest)
+    /// depth of each 2x2 block, one mip at a time.
+    #[cfg_attr(feature = "flame_it", flame)]
+    fn build_hiz_pyramid(&mut self, camera: &Camera) {
+        if self.depth_pyramid.borrow().is_none() {
+            *self.depth_pyramid.borrow_mut() = Some(DepthPyramid::new(&self.gl, self.screen_size));
+        }
+
+        let depth_prepass = RenderTexture::new_depth(&self.gl, self.screen_size.0, self.screen_size.1);
+        depth_prepass.bind_frame_buffer(&self.gl);
+        self.gl.viewport(0, 0, self.screen_size.0, self.screen_size.1);
+        self.gl.clear(BufferBit::Depth);
+
+        // A distinct shader from the shadow pass's depth_only.glsl: that one
+        // declares uLightSpaceMatrix/uMMatrix, this one uMVPMatrix, and
+        // get_or_compile_program caches by path, so sharing a path here would
+        // silently hand one of the two passes a program with the wrong
+        // uniforms declared.
+        let depth_prog = match self.get_or_compile_program("shaders/hiz_depth_only.glsl", &[]) {
+            Ok(p) => p,
+            Err(AssetError::NotReady) => return,
+            Err(err) => panic!(format!("Failed to compile Hi-Z depth-only shader, reason {:?}", err)),
+        };
+        let pv = camera.perspective(self.screen_size) * camera.v;
+
+        for obj in self.objects.iter() {
+            obj.upgrade().map(|obj| {
+                let object = match obj.try_borrow() {
+                    Ok(object) => object,
+                    Err(_) => return,
+                };
+
+                if !object.active {
+                    return;
+                }
+
+                if let Some((mesh, _)) = object.find_component::<Mesh>() {
+                    for surface in mesh.surfaces.iter() {
+                        if surface.material.render_queue != RenderQueue::Opaque {
+                            continue;
+                        }
+
+                        let bounds = match surface.buffer.bounds() {
+                            Some(b) => b,
+                            None => continue,
+                        };
+
+                        let scale = get_max_scale(&object.transform.local_scale());
+                        if bounds.r * scale < hiz::OCCLUDER_MIN_RADIUS {
+                            continue;
+                        }
+
+                        let m = compute_model_m(&*object);
+
+                        if depth_prog.bind(&self.gl).is_err() {
+                            continue;
+                        }
+
+                        depth_prog.set("uMVPMatrix", pv * m);
+                        depth_prog.commit(&self.gl);
+
+                        if surface.buffer.bind(&self.gl, &depth_prog).is_ok() {
+                            surface.buffer.render(&self.gl);
+                            surface.buffer.unbind(&self.gl);
+                        }
+                    }
+                }
+            });
+        }
+
+        depth_prepass.unbind_frame_buffer(&self.gl);
+
+        let gl = &self.gl;
+        let mut pyramid = self.depth_pyramid.borrow_mut();
+        let pyramid = pyramid.as_mut().unwrap();
+
+        pyramid.build(gl, &depth_prepass, &mut |gl, src, dst| {
+            self.asset_system
+                .hiz_downsample_material()
+                .blit_max(gl, src, dst);
+        });
+    }
+
+    /// Shared by `render_pass_with_material` and `render_graph_scene_node`:
+    /// clear, gather every active surface within `camera`'s frustum (Hi-Z
+    /// culled when `clear_option.occlusion_culling` is set), and sort the
+    /// opaque and transparent queues, leaving target binding and the final `render_commands` dispatch
+    /// (which differ: whole-queue-list vs. a single `RenderQueue`, material
+    /// override vs. none) to the caller.
+    fn gather_and_sort_render_queue(
+        &mut self,
+        ctx: &mut EngineContext,
+        camera: &Camera,
+        clear_option: ClearOption,
+    ) -> RenderQueueList {
+        self.clear(clear_option);
+
+        self.prepare_ctx(ctx);
+
+        let mut render_q = RenderQueueList::new();
+
+        let frustum = camera.calc_frustum(self.screen_size);
+
+        let lights: Vec<_> = ctx.point_lights
+            .iter()
+            .map(|c| {
+                let plight = c.try_as::<Light>().unwrap();
+                let plight = plight.borrow();
+                (plight.position(), plight.radius(), plight.color(), plight.intensity())
+            })
+            .collect();
+
+        let mut cluster_state = self.cluster_state.borrow_mut();
+        cluster_state.build(camera, self.screen_size, &lights);
+        ctx.cluster_buffers = Some(ClusterGpuBuffers::upload(&self.gl, &cluster_state));
+
+        let occlusion_culling = clear_option.occlusion_culling;
+        if occlusion_culling {
+            self.build_hiz_pyramid(camera);
+        }
+
+        let depth_pyramid = self.depth_pyramid.borrow();
+        let hiz = if occlusion_culling {
+            depth_pyramid.as_ref()
+        } else {
+            None
+        };
+
+        // gather commands
+        for obj in self.objects.iter() {
+            obj.upgrade().map(|obj| {
+                if let Ok(object) = obj.try_borrow() {
+                    self.gather_render_commands(&object, &camera.eye(), &frustum, camera, hiz, &mut render_q)
+                }
+            });
+        }
+
+        // Sort the opaque queue
+        render_q
+            .get_mut(&RenderQueue::Opaque)
+            .unwrap()
+            .sort_by_cam_distance_reverse();
+
+        // Sort the transparent queue
+        render_q
+            .get_mut(&RenderQueue::Transparent)
+            .unwrap()
+            .sort_by_cam_distance();
+
+        ctx.stats.surfaces_count = render_q.surface_count() as u32;
+        ctx.stats.transparent_count = render_q
+            .get(&RenderQueue::Transparent)
+            .unwrap()
+            .commands
+            .len() as u32;
+        ctx.stats.opaque_count = render_q.get(&RenderQueue::Opaque).unwrap().commands.len() as u32;
+
+        render_q
+    }
+
+    #[cfg_attr(feature = "flame_it", flame)]
+    pub fn render_pass_with_material(
+        &mut self,
+        camera: &Camera,
+        material: Option<&Rc<Material>>,
+        clear_option: ClearOption,
+    ) {
+        let mut ctx: EngineContext = EngineContext::new(self.stats);
+
+        if let Some(ref rt) = camera.render_texture {
+            rt.bind_frame_buffer(&self.gl);
+        }
+
+        match camera.rect {
+            Some(((x, y), (w, h))) => {
+                self.gl.viewport(x, y, w, h);
+            }
+            None => {
+                self.gl
+                    .viewport(0, 0, self.screen_size.0, self.screen_size.1);
+            }
+        }
+
+        let render_q = self.gather_and_sort_render_queue(&mut ctx, camera, clear_option);
+
+        for (_, q) in render_q.iter() {
+            self.render_commands(&mut ctx, &q, camera, material);
+        }
+
+        if let Some(ref rt) = camera.render_texture {
+            rt.unbind_frame_buffer(&self.gl);
+        }
+
+        self.stats = ctx.stats;
+    }
+
+    #[cfg_attr(feature = "flame_it", flame)]
+    pub fn render_pass(&mut self, camera: &Camera, clear_option: ClearOption) {
+        self.render_pass_with_material(camera, None, clear_option);
+    }
+
+    /// Like `render_pass_with_material`, but restricted to a single
+    /// `RenderQueue` (or every queue, when `None`) and targeting a
+    /// `RenderGraphNode::Scene`'s own `output` texture instead of the
+    /// camera's.
+    #[cfg_attr(feature = "flame_it", flame)]
+    fn render_graph_scene_node(
+        &mut self,
+        camera: &Camera,
+        queue: Option<RenderQueue>,
+        output: Option<&Rc<RenderTexture>>,
+        clear_option: ClearOption,
+    ) {
+        let mut ctx: EngineContext = EngineContext::new(self.stats);
+
+        if let Some(rt) = output {
+            rt.bind_frame_buffer(&self.gl);
+        }
+
+        self.gl
+            .viewport(0, 0, self.screen_size.0, self.screen_size.1);
+
+        let render_q = self.gather_and_sort_render_queue(&mut ctx, camera, clear_option);
+
+        match queue {
+            Some(q) => self.render_commands(&mut ctx, render_q.get(&q).unwrap(), camera, None),
+            None => {
+                for (_, q) in render_q.iter() {
+                    self.render_commands(&mut ctx, &q, camera, None);
+                }
+            }
+        }
+
+        if let Some(rt) = output {
+            rt.unbind_frame_buffer(&self.gl);
+        }
+
+        self.stats = ctx.stats;
+    }
+
+    /// Execute a `RenderGraphNode::FullscreenQuad`: bind each named input to
+    /// the texture its producing node rendered into, then draw `material`
+    /// over a single full-screen triangle into `output` (or the screen).
+    #[cfg_attr(feature = "flame_it", flame)]
+    fn render_graph_fullscreen_node(
+        &mut self,
+        graph: &RenderGraph,
+        material: &Rc<Material>,
+        inputs: &[(&'static str, &'static str)],
+        output: Option<&Rc<RenderTexture>>,
+    ) {
+        let mut ctx: EngineContext = EngineContext::new(self.stats);
+
+        if let Some(rt) = output {
+            rt.bind_frame_buffer(&self.gl);
+        }
+
+        self.gl
+            .viewport(0, 0, self.screen_size.0, self.screen_size.1);
+
+        // `setup_light` (via `setup_material`) needs the main light.
+        self.prepare_ctx(&mut ctx);
+
+        if self.setup_material(&mut ctx, material).is_ok() {
+            let prog = ctx.prog.upgrade().unwrap();
+
+            for &(name, src) in inputs {
+                if let Some(tex) = graph.output_of(src) {
+                    tex.bind_tex(&prog, name);
+                }
+            }
+
+            prog.commit(&self.gl);
+            self.asset_system.fullscreen_quad().draw(&self.gl, &prog);
+        }
+
+        if let Some(rt) = output {
+            rt.unbind_frame_buffer(&self.gl);
+        }
+
+        self.stats = ctx.stats;
+    }
+
+    /// Walk `graph` in topological order, running each `Scene` node as a
+    /// queue-filtered render pass and each `FullscreenQuad` node as a
+    /// post-process draw, wiring named inputs to the textures earlier
+    /// nodes rendered into.
+    #[cfg_attr(feature = "flame_it", flame)]
+    pub fn render_graph(&mut self, graph: &RenderGraph, camera: &Camera, clear_option: ClearOption) {
+        for i in graph.topo_sorted() {
+            match graph.nodes()[i] {
+                RenderGraphNode::Scene {
+                    queue,
+                    ref output,
+                    ..
+                } => {
+                    self.render_graph_scene_node(camera, queue, output.as_ref(), clear_option);
+                }
+                RenderGraphNode::FullscreenQuad {
+                    ref material,
+                    ref inputs,
+                    ref output,
+                    ..
+                } => {
+                    self.render_graph_fullscreen_node(graph, material, inputs, output.as_ref());
+                }
+            }
+        }
+    }
+
+    pub fn main_camera(&self) -> Option<Arc<Component>> {
+        let mut found = self.current_camera.borrow_mut();
+        match *found {
+            None => *found = self.find_component::<Camera>().map(|c| c.clone()),
+            _ => (),
+        }
+
+        if let Some(ref c) = *found {
+            return Some(c.clone());
+        }
+
+        None
+    }
+
+    #[cfg_attr(feature = "flame_it", flame)]
+    pub fn render(&mut self, clear_option: ClearOption) {
+        imgui::pre_render(self);
+
+        if let Some(ref camera) = self.main_camera() {
+            let camera = camera.try_as::<Camera>().unwrap();
+            self.render_shadow_pass(&camera.borrow());
+            self.render_point_shadow_pass();
+
+            match self.render_graph.take() {
+                Some(graph) => {
+                    self.render_graph(&graph, &camera.borrow(), clear_option);
+                    self.render_graph = Some(graph);
+                }
+                None => self.render_pass(&camera.borrow(), clear_option),
+            }
+        } else {
+            // We dont have a main camera here, just clean the screen.
+            self.clear(clear_option);
+        }
+    }
+
+    pub fn new(webgl_ctx: WebGLContext, size: (u32, u32), hidpi: f32) -> Engine<A> {
+        let gl = WebGLRenderingContext::new(webgl_ctx);
+
+        /*=========Drawing the triangle===========*/
+
+        // Clear the canvas
+        gl.clear_color(0.5, 0.5, 0.5, 1.0);
+
+        // Enable alpha blending
+        gl.enable(Flag::Blend as i32);
+
+        // Clear the color buffer bit
+        gl.clear(BufferBit::Color);
+        gl.clear(BufferBit::Depth);
+        gl.blend_func(BlendMode::SrcAlpha, BlendMode::OneMinusSrcAlpha);
+
+        // Set the view port
+        gl.viewport(0, 0, size.0, size.1);
+
+        let gui_tree = SceneTree::new();
+
+        Engine {
+            gl: gl,
+            objects: vec![],
+            program_cache: RefCell::new(HashMap::new()),
+            asset_system: Box::new(A::new()),
+            gui_context: Rc::new(RefCell::new(imgui::Context::new(gui_tree))),
+            screen_size: size,
+            hidpi: hidpi,
+            current_camera: RefCell::new(None),
+            stats: Default::default(),
+            shadow_map: RefCell::new(None),
+            point_shadow_maps: RefCell::new(Vec::new()),
+            cluster_state: RefCell::new(ClusterState::default()),
+            depth_pyramid: RefCell::new(None),
+            render_graph: None,
+        }
+    }
+
+    pub fn begin(&mut self) {
+        imgui::begin();
+
+        self.asset_system_mut().step();
+    }
+
+    pub fn end(&mut self) {
+        // drop all gameobjects if there are no other references
+        self.objects.retain(|obj| obj.upgrade().is_some());
+
+        // drop camera cache if it is only by holded by ourself
+        let mut cam_mut = self.current_camera.borrow_mut();
+        if let Some(ref c) = *cam_mut {
+            if Arc::strong_count(&c) == 1 {
+                cam_mut.take();
+            }
+        }
+    }
+}
+
+impl<A: AssetSystem> IEngine for Engine<A> {
+    fn new_game_object(&mut self, parent: &GameObject) -> Rc<RefCell<GameObject>> {
+        let go = parent.tree().new_node(parent);
+
+        self.objects.push(Rc::downgrade(&go));
+        go
+    }
+
+    fn gui_context(&mut self) -> Rc<RefCell<imgui::Context>> {
+        self.gui_context.clone()
+    }
+
+    fn asset_system<'a>(&'a self) -> &'a AssetSystem {
+        &*self.asset_system
+    }
+
+    fn asset_system_mut<'a>(&'a mut self) -> &'a mut AssetSystem {
+        &mut *self.asset_system
+    }
+
+    fn screen_size(&self) -> (u32, u32) {
+        self.screen_size
+    }
+
+    fn hidpi_factor(&self) -> f32 {
+        self.hidpi
+    }
+}
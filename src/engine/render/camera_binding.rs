@@ -0,0 +1,46 @@
+/// A named camera uniform a `ShaderProgram` may declare. `ShaderProgram`
+/// discovers the subset a given program actually uses by enumerating its
+/// active uniforms at link time (see `ShaderProgram::compile`), so
+/// `Engine::setup_camera` only computes and uploads the matrices that
+/// program asked for instead of the fixed
+/// `uMVMatrix`/`uPMatrix`/`uNMatrix`/`uViewPos` set every shader used to
+/// be forced to declare.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CameraBinding {
+    /// `uPVMatrix`: projection * view, for shaders that only transform a
+    /// position straight into clip space.
+    ViewProj,
+    /// `uMVMatrix`: view * model, for shaders that need a view-space
+    /// position (e.g. view-space fog or lighting).
+    View,
+    /// `uPMatrix`: the projection matrix alone.
+    Proj,
+    /// `uViewPos`: the camera's world-space eye position.
+    ViewPos,
+    /// `uNMatrix`: inverse-transpose of the model matrix, for transforming
+    /// normals into world space.
+    Normal,
+}
+
+impl CameraBinding {
+    /// The uniform name a program declares this binding under.
+    pub fn uniform_name(&self) -> &'static str {
+        match *self {
+            CameraBinding::ViewProj => "uPVMatrix",
+            CameraBinding::View => "uMVMatrix",
+            CameraBinding::Proj => "uPMatrix",
+            CameraBinding::ViewPos => "uViewPos",
+            CameraBinding::Normal => "uNMatrix",
+        }
+    }
+
+    /// Every binding `ShaderProgram` knows how to discover, in a fixed
+    /// order so `ShaderProgram::camera_bindings` lists them deterministically.
+    pub const ALL: [CameraBinding; 5] = [
+        CameraBinding::ViewProj,
+        CameraBinding::View,
+        CameraBinding::Proj,
+        CameraBinding::ViewPos,
+        CameraBinding::Normal,
+    ];
+}
@@ -0,0 +1,206 @@
+use webgl::*;
+use na::*;
+
+use engine::render::Camera;
+use engine::render::Light;
+use engine::render::DataTexture;
+
+/// Cluster grid dimensions. Z uses exponential slicing so near-camera
+/// clusters (where most lights matter) stay thin.
+pub const CLUSTER_X: usize = 16;
+pub const CLUSTER_Y: usize = 9;
+pub const CLUSTER_Z: usize = 24;
+pub const MAX_LIGHTS_PER_CLUSTER: usize = 64;
+
+/// One point light's packed data, as uploaded to the light-data buffer.
+#[derive(Copy, Clone)]
+pub struct PackedPointLight {
+    pub position: Point3<f32>,
+    pub radius: f32,
+    pub color: Vector3<f32>,
+    pub intensity: f32,
+}
+
+/// Per-frame clustered-lighting state: which point lights touch which
+/// cluster, ready to upload as the two GPU buffers the shader walks.
+#[derive(Default)]
+pub struct ClusterState {
+    /// Offset + count into `light_indices`, flattened in x + y*X + z*X*Y order.
+    pub cluster_offsets: Vec<(u32, u32)>,
+    /// Flat list of point-light indices, grouped by cluster.
+    pub light_indices: Vec<u32>,
+    pub lights: Vec<PackedPointLight>,
+    pub enabled: bool,
+}
+
+fn z_slice(k: usize, near: f32, far: f32) -> f32 {
+    near * (far / near).powf(k as f32 / CLUSTER_Z as f32)
+}
+
+impl ClusterState {
+    /// Re-assign every point light to the clusters its bounding sphere
+    /// intersects. `near`/`far` come from the active camera's projection.
+    pub fn build(&mut self, camera: &Camera, screen_size: (u32, u32), lights: &[(Point3<f32>, f32, Vector3<f32>, f32)]) {
+        self.lights.clear();
+        self.light_indices.clear();
+        self.cluster_offsets.clear();
+        self.cluster_offsets
+            .resize(CLUSTER_X * CLUSTER_Y * CLUSTER_Z, (0, 0));
+
+        let (near, far) = camera.near_far();
+        let view = camera.v;
+
+        self.lights.extend(lights.iter().map(|&(position, radius, color, intensity)| {
+            PackedPointLight {
+                position,
+                radius,
+                color,
+                intensity,
+            }
+        }));
+
+        // Bucket each light into every cluster its bounding sphere overlaps,
+        // then flatten the per-cluster buckets into the offset/count table
+        // plus a single contiguous index list.
+        let mut buckets: Vec<Vec<u32>> = vec![Vec::new(); CLUSTER_X * CLUSTER_Y * CLUSTER_Z];
+
+        for (light_index, light) in self.lights.iter().enumerate() {
+            let view_pos = view.transform_point(&light.position);
+
+            for k in 0..CLUSTER_Z {
+                let z0 = -z_slice(k, near, far);
+                let z1 = -z_slice(k + 1, near, far);
+
+                if view_pos.z + light.radius < z1 || view_pos.z - light.radius > z0 {
+                    continue;
+                }
+
+                for j in 0..CLUSTER_Y {
+                    for i in 0..CLUSTER_X {
+                        let cluster_screen_aabb = cluster_screen_bounds(i, j, screen_size);
+
+                        if !sphere_overlaps_screen_aabb(&camera, &light.position, light.radius, cluster_screen_aabb) {
+                            continue;
+                        }
+
+                        let idx = i + j * CLUSTER_X + k * CLUSTER_X * CLUSTER_Y;
+                        if buckets[idx].len() < MAX_LIGHTS_PER_CLUSTER {
+                            buckets[idx].push(light_index as u32);
+                        }
+                    }
+                }
+            }
+        }
+
+        for (idx, bucket) in buckets.into_iter().enumerate() {
+            let offset = self.light_indices.len() as u32;
+            self.light_indices.extend(bucket.iter());
+            self.cluster_offsets[idx] = (offset, self.light_indices.len() as u32 - offset);
+        }
+    }
+}
+
+fn cluster_screen_bounds(i: usize, j: usize, screen_size: (u32, u32)) -> ((f32, f32), (f32, f32)) {
+    let cw = screen_size.0 as f32 / CLUSTER_X as f32;
+    let ch = screen_size.1 as f32 / CLUSTER_Y as f32;
+
+    (
+        (i as f32 * cw, j as f32 * ch),
+        ((i + 1) as f32 * cw, (j + 1) as f32 * ch),
+    )
+}
+
+/// GPU-side mirror of `ClusterState`: the cluster offset/count table, the
+/// flat light-index list, and the packed per-light data, each as a small
+/// float data texture so a WebGL1 fragment shader can sample them directly.
+pub struct ClusterGpuBuffers {
+    pub offset_tex: DataTexture,
+    pub index_tex: DataTexture,
+    pub light_data_tex: DataTexture,
+}
+
+impl ClusterGpuBuffers {
+    pub fn upload(gl: &WebGLRenderingContext, state: &ClusterState) -> ClusterGpuBuffers {
+        let offsets: Vec<f32> = state
+            .cluster_offsets
+            .iter()
+            .flat_map(|&(offset, count)| vec![offset as f32, count as f32])
+            .collect();
+
+        let indices: Vec<f32> = state.light_indices.iter().map(|&i| i as f32).collect();
+
+        let light_data: Vec<f32> = state
+            .lights
+            .iter()
+            .flat_map(|l| {
+                vec![
+                    l.position.x,
+                    l.position.y,
+                    l.position.z,
+                    l.radius,
+                    l.color.x,
+                    l.color.y,
+                    l.color.z,
+                    l.intensity,
+                ]
+            })
+            .collect();
+
+        ClusterGpuBuffers {
+            offset_tex: DataTexture::from_f32(gl, &offsets, state.cluster_offsets.len().max(1) as u32, 2),
+            index_tex: DataTexture::from_f32(gl, &indices, indices.len().max(1) as u32, 1),
+            light_data_tex: DataTexture::from_f32(gl, &light_data, state.lights.len().max(1) as u32, 4),
+        }
+    }
+}
+
+fn sphere_overlaps_screen_aabb(
+    camera: &Camera,
+    center: &Point3<f32>,
+    radius: f32,
+    aabb: ((f32, f32), (f32, f32)),
+) -> bool {
+    let screen = camera.project_to_screen(center, radius);
+
+    let ((min_x, min_y), (max_x, max_y)) = aabb;
+    let ((smin_x, smin_y), (smax_x, smax_y)) = screen;
+
+    smin_x <= max_x && smax_x >= min_x && smin_y <= max_y && smax_y >= min_y
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn z_slice_endpoints_match_near_and_far() {
+        assert_eq!(z_slice(0, 1.0, 100.0), 1.0);
+        assert!((z_slice(CLUSTER_Z, 1.0, 100.0) - 100.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn z_slice_is_monotonically_increasing() {
+        let mut prev = z_slice(0, 0.5, 500.0);
+        for k in 1..=CLUSTER_Z {
+            let next = z_slice(k, 0.5, 500.0);
+            assert!(next > prev, "slice {} did not grow: {} -> {}", k, prev, next);
+            prev = next;
+        }
+    }
+
+    #[test]
+    fn cluster_screen_bounds_tile_the_full_screen_without_gaps() {
+        let screen_size = (1600, 900);
+
+        let first = cluster_screen_bounds(0, 0, screen_size);
+        assert_eq!(first.0, (0.0, 0.0));
+
+        let last = cluster_screen_bounds(CLUSTER_X - 1, CLUSTER_Y - 1, screen_size);
+        assert_eq!(last.1, (screen_size.0 as f32, screen_size.1 as f32));
+
+        // Adjacent clusters share an edge, so the grid has no gaps or overlaps.
+        let left = cluster_screen_bounds(2, 3, screen_size);
+        let right = cluster_screen_bounds(3, 3, screen_size);
+        assert_eq!(left.1 .0, right.0 .0);
+    }
+}
@@ -0,0 +1,121 @@
+use na::*;
+
+use engine::render::{Camera, RenderTexture};
+
+/// Conservative depth mip pyramid built from an early depth prepass: each
+/// mip stores the *farthest* (max) depth of its 2x2 block below it, so
+/// sampling a coarse mip never under-estimates occluder distance.
+pub struct DepthPyramid {
+    pub levels: Vec<RenderTexture>,
+    pub size: (u32, u32),
+}
+
+impl DepthPyramid {
+    pub fn new(gl: &::webgl::WebGLRenderingContext, size: (u32, u32)) -> DepthPyramid {
+        let mut levels = Vec::new();
+        let mut w = size.0;
+        let mut h = size.1;
+
+        levels.push(RenderTexture::new_depth(gl, w, h));
+
+        while w > 1 || h > 1 {
+            w = (w / 2).max(1);
+            h = (h / 2).max(1);
+            levels.push(RenderTexture::new_depth(gl, w, h));
+        }
+
+        DepthPyramid { levels, size }
+    }
+
+    /// Downsample `depth_prepass` into mip 0, then repeatedly downsample
+    /// each level into the next using a max filter, via a full-screen
+    /// "max of 2x2" blit material supplied by the caller.
+    pub fn build(
+        &mut self,
+        gl: &::webgl::WebGLRenderingContext,
+        depth_prepass: &RenderTexture,
+        downsample: &mut FnMut(&::webgl::WebGLRenderingContext, &RenderTexture, &RenderTexture),
+    ) {
+        downsample(gl, depth_prepass, &self.levels[0]);
+
+        for i in 0..self.levels.len() - 1 {
+            let (left, right) = self.levels.split_at(i + 1);
+            downsample(gl, &left[i], &right[0]);
+        }
+    }
+
+    /// Read back the conservative (max/farthest) depth stored for the given
+    /// screen AABB at the given mip level.
+    pub fn sample_depth(&self, mip: usize, aabb: ((f32, f32), (f32, f32))) -> f32 {
+        self.levels[mip].sample_depth_aabb(aabb)
+    }
+
+    /// Pick the mip level where the screen AABB spans roughly 1-2 texels.
+    pub fn mip_for_screen_aabb(&self, aabb: ((f32, f32), (f32, f32))) -> usize {
+        let ((min_x, min_y), (max_x, max_y)) = aabb;
+        let extent = (max_x - min_x).max(max_y - min_y).max(1.0);
+
+        let mut mip = (extent.log2().floor()) as i32;
+        mip = mip.max(0).min(self.levels.len() as i32 - 1);
+        mip as usize
+    }
+}
+
+/// Project a world-space bounding sphere to a conservative screen-space
+/// AABB, widening by the sphere's radius so a coarse mip test never culls a
+/// surface that's only partially covered.
+pub fn project_bounding_sphere(
+    camera: &Camera,
+    screen_size: (u32, u32),
+    center: &Point3<f32>,
+    radius: f32,
+) -> ((f32, f32), (f32, f32)) {
+    camera.project_sphere_to_screen_aabb(center, radius, screen_size)
+}
+
+/// Project a world-space point into the same device-depth space (`[0, 1]`
+/// after the perspective divide, matching the default WebGL depth range)
+/// the pyramid's mips were downsampled from, so a CPU-side distance never
+/// gets compared against a stored depth in the wrong units.
+pub fn device_depth(camera: &Camera, screen_size: (u32, u32), point: &Point3<f32>) -> f32 {
+    let clip = camera.perspective(screen_size) * camera.v * point.to_homogeneous();
+
+    (clip.z / clip.w) * 0.5 + 0.5
+}
+
+/// Surfaces whose world-space bounding radius is at least this large are
+/// rendered in the early depth prepass as occluders; everything else only
+/// ever gets *tested* against the resulting pyramid.
+pub const OCCLUDER_MIN_RADIUS: f32 = 2.0;
+
+/// Conservative Hi-Z test: the surface is occluded if the mip's stored
+/// (farthest-in-block) depth is still nearer than the surface's closest
+/// point, i.e. something in front of it fully covers the block. Both
+/// depths must already be in the pyramid's device-depth space (see
+/// `device_depth`) — comparing a world-space distance here would compare
+/// values in unrelated units.
+pub fn is_occluded(nearest_surface_depth: f32, sampled_occluder_depth: f32) -> bool {
+    sampled_occluder_depth < nearest_surface_depth
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearer_occluder_hides_the_surface() {
+        // Smaller device depth is nearer the camera, so an occluder sampled
+        // at 0.2 in front of a surface at 0.5 should occlude it.
+        assert!(is_occluded(0.5, 0.2));
+    }
+
+    #[test]
+    fn farther_occluder_does_not_hide_the_surface() {
+        assert!(!is_occluded(0.2, 0.5));
+    }
+
+    #[test]
+    fn equal_depth_does_not_occlude() {
+        assert!(!is_occluded(0.4, 0.4));
+    }
+}
@@ -0,0 +1,197 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use na::{Matrix4, Point3, Vector2, Vector3};
+use webgl::*;
+
+use engine::render::camera_binding::CameraBinding;
+
+/// A uniform value staged by `ShaderProgram::set`, uploaded on `commit`.
+#[derive(Clone)]
+enum UniformValue {
+    Float(f32),
+    Int(i32),
+    Vec2(Vector2<f32>),
+    Vec3(Vector3<f32>),
+    Mat4(Matrix4<f32>),
+}
+
+/// Types `ShaderProgram::set` accepts as a uniform value.
+pub trait IntoUniformValue {
+    fn into_uniform_value(self) -> UniformValue;
+}
+
+impl IntoUniformValue for f32 {
+    fn into_uniform_value(self) -> UniformValue {
+        UniformValue::Float(self)
+    }
+}
+
+impl IntoUniformValue for i32 {
+    fn into_uniform_value(self) -> UniformValue {
+        UniformValue::Int(self)
+    }
+}
+
+impl IntoUniformValue for Vector2<f32> {
+    fn into_uniform_value(self) -> UniformValue {
+        UniformValue::Vec2(self)
+    }
+}
+
+impl IntoUniformValue for Vector3<f32> {
+    fn into_uniform_value(self) -> UniformValue {
+        UniformValue::Vec3(self)
+    }
+}
+
+impl IntoUniformValue for Point3<f32> {
+    fn into_uniform_value(self) -> UniformValue {
+        UniformValue::Vec3(self.coords)
+    }
+}
+
+impl IntoUniformValue for Matrix4<f32> {
+    fn into_uniform_value(self) -> UniformValue {
+        UniformValue::Mat4(self)
+    }
+}
+
+/// A linked GL program plus the uniforms it actually declares, discovered
+/// once at link time by enumerating `ACTIVE_UNIFORMS` instead of every
+/// caller guessing (or every shader being forced to declare a fixed set).
+/// `set`/`commit` stage then flush uniform writes the same way the rest of
+/// the engine defers state changes (see `MaterialState::commit`), so
+/// repeated `set` calls for a uniform this program doesn't declare are
+/// simply dropped instead of every call site having to check first.
+pub struct ShaderProgram {
+    program: WebGLProgram,
+    declared_uniforms: HashMap<String, WebGLUniformLocation>,
+    camera_bindings: Vec<CameraBinding>,
+    pending: RefCell<HashMap<String, UniformValue>>,
+}
+
+impl ShaderProgram {
+    /// Compile and link `source` as both stages of a program, selecting
+    /// each stage with `#define VERTEX`/`#define FRAGMENT` ahead of the
+    /// shared GLSL so the two stages can live in one file (the shape
+    /// `shader_preprocessor::preprocess` hands back).
+    pub fn compile(gl: &WebGLRenderingContext, source: &str) -> ShaderProgram {
+        let vs = Self::compile_stage(gl, ShaderKind::Vertex, "VERTEX", source);
+        let fs = Self::compile_stage(gl, ShaderKind::Fragment, "FRAGMENT", source);
+
+        let program = gl.create_program();
+        gl.attach_shader(&program, &vs);
+        gl.attach_shader(&program, &fs);
+        gl.link_program(&program);
+
+        if !gl.get_program_parameter(&program, ProgramParameter::LinkStatus) {
+            panic!(format!(
+                "Failed to link shader program: {}",
+                gl.get_program_info_log(&program)
+            ));
+        }
+
+        let declared_uniforms = Self::discover_active_uniforms(gl, &program);
+        let camera_bindings = CameraBinding::ALL
+            .iter()
+            .cloned()
+            .filter(|binding| declared_uniforms.contains_key(binding.uniform_name()))
+            .collect();
+
+        ShaderProgram {
+            program,
+            declared_uniforms,
+            camera_bindings,
+            pending: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn compile_stage(
+        gl: &WebGLRenderingContext,
+        kind: ShaderKind,
+        stage_define: &str,
+        source: &str,
+    ) -> WebGLShader {
+        let shader = gl.create_shader(kind);
+        gl.shader_source(&shader, &format!("#define {}\n{}", stage_define, source));
+        gl.compile_shader(&shader);
+
+        if !gl.get_shader_parameter(&shader, ShaderParameter::CompileStatus) {
+            panic!(format!(
+                "Failed to compile {:?} shader: {}",
+                kind,
+                gl.get_shader_info_log(&shader)
+            ));
+        }
+
+        shader
+    }
+
+    /// Every uniform location the driver reports as active for this
+    /// program, keyed by name, captured once at link time.
+    fn discover_active_uniforms(
+        gl: &WebGLRenderingContext,
+        program: &WebGLProgram,
+    ) -> HashMap<String, WebGLUniformLocation> {
+        let count = gl.get_program_parameter(program, ProgramParameter::ActiveUniforms) as u32;
+
+        (0..count)
+            .filter_map(|i| gl.get_active_uniform(program, i))
+            .filter_map(|info| {
+                gl.get_uniform_location(program, &info.name)
+                    .map(|loc| (info.name, loc))
+            })
+            .collect()
+    }
+
+    /// The subset of `CameraBinding::ALL` this program declares, in
+    /// `CameraBinding::ALL` order, so `Engine::setup_camera` only computes
+    /// and uploads the matrices this program asked for.
+    pub fn camera_bindings(&self) -> &[CameraBinding] {
+        &self.camera_bindings
+    }
+
+    /// Whether this program declares a uniform named `name` at all — used
+    /// for uniforms (like `uPVSkyboxMatrix`) that aren't one of the named
+    /// `CameraBinding`s.
+    pub fn declares_uniform(&self, name: &str) -> bool {
+        self.declared_uniforms.contains_key(name)
+    }
+
+    pub fn bind(&self, gl: &WebGLRenderingContext) -> Result<(), ()> {
+        gl.use_program(&self.program);
+        Ok(())
+    }
+
+    /// Stage `value` for `name`; a no-op if this program doesn't declare
+    /// `name`, so callers can set every uniform a surface might want
+    /// without checking `declares_uniform` themselves.
+    pub fn set<T: IntoUniformValue>(&self, name: &str, value: T) {
+        if !self.declared_uniforms.contains_key(name) {
+            return;
+        }
+
+        self.pending
+            .borrow_mut()
+            .insert(name.to_string(), value.into_uniform_value());
+    }
+
+    /// Flush every uniform staged by `set` since the last `commit`.
+    pub fn commit(&self, gl: &WebGLRenderingContext) {
+        for (name, value) in self.pending.borrow_mut().drain() {
+            let location = match self.declared_uniforms.get(&name) {
+                Some(location) => location,
+                None => continue,
+            };
+
+            match value {
+                UniformValue::Float(v) => gl.uniform_1f(location, v),
+                UniformValue::Int(v) => gl.uniform_1i(location, v),
+                UniformValue::Vec2(v) => gl.uniform_2f(location, v.x, v.y),
+                UniformValue::Vec3(v) => gl.uniform_3f(location, v.x, v.y, v.z),
+                UniformValue::Mat4(v) => gl.uniform_matrix_4fv(location, false, v.as_slice()),
+            }
+        }
+    }
+}
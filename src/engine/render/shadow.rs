@@ -0,0 +1,127 @@
+use webgl::*;
+use na::*;
+
+use engine::render::{Camera, Frustum, RenderTexture};
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ShadowFilterMode {
+    None,
+    Hardware2x2,
+    Pcf,
+    Pcss,
+}
+
+#[derive(Copy, Clone)]
+pub struct ShadowSettings {
+    pub resolution: u32,
+    pub depth_bias: f32,
+    pub filter: ShadowFilterMode,
+    pub kernel_radius: f32,
+    pub light_size: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        ShadowSettings {
+            resolution: 1024,
+            depth_bias: 0.002,
+            filter: ShadowFilterMode::Pcf,
+            kernel_radius: 3.0,
+            light_size: 0.2,
+        }
+    }
+}
+
+/// Depth render target plus the matrix used to render it, for the
+/// directional shadow pass.
+pub struct ShadowMap {
+    pub depth_texture: RenderTexture,
+    pub light_space_matrix: Matrix4<f32>,
+    pub settings: ShadowSettings,
+}
+
+impl ShadowMap {
+    pub fn new(gl: &WebGLRenderingContext, settings: ShadowSettings) -> ShadowMap {
+        ShadowMap {
+            depth_texture: RenderTexture::new_depth(gl, settings.resolution, settings.resolution),
+            light_space_matrix: Matrix4::identity(),
+            settings,
+        }
+    }
+
+    /// Fit an orthographic projection around the camera frustum corners, as
+    /// seen from the light, and store the resulting view-projection matrix.
+    pub fn fit_to_frustum(&mut self, light_dir: &Vector3<f32>, camera: &Camera, frustum: &Frustum) {
+        let corners = frustum.corners();
+
+        let eye = Point3::new(0.0, 0.0, 0.0) - light_dir * 1000.0;
+        let light_view = Matrix4::look_at_rh(&eye, &Point3::new(0.0, 0.0, 0.0), &Vector3::y());
+
+        let mut min = Vector3::new(::std::f32::MAX, ::std::f32::MAX, ::std::f32::MAX);
+        let mut max = Vector3::new(::std::f32::MIN, ::std::f32::MIN, ::std::f32::MIN);
+
+        for corner in corners.iter() {
+            let p = light_view.transform_point(corner);
+            min = min.inf(&p.coords);
+            max = max.sup(&p.coords);
+        }
+
+        let light_proj = Matrix4::new_orthographic(min.x, max.x, min.y, max.y, -max.z, -min.z);
+
+        self.light_space_matrix = light_proj * light_view;
+        let _ = camera;
+    }
+}
+
+/// The cube map's 6 faces, in the conventional +X,-X,+Y,-Y,+Z,-Z order, as
+/// (look direction, up) pairs.
+fn cube_face_directions() -> [(Vector3<f32>, Vector3<f32>); 6] {
+    [
+        (Vector3::x(), -Vector3::y()),
+        (-Vector3::x(), -Vector3::y()),
+        (Vector3::y(), Vector3::z()),
+        (-Vector3::y(), -Vector3::z()),
+        (Vector3::z(), -Vector3::y()),
+        (-Vector3::z(), -Vector3::y()),
+    ]
+}
+
+/// A point light's shadow, stored as 6 independent depth targets (one per
+/// cube face) rather than a native depth cubemap, since distance-based
+/// comparison only needs each face to report *linear distance from the
+/// light*, not a GL cubemap's filtering behavior. `render_point_shadow_pass`
+/// writes that distance into each face; the shading pass compares a
+/// fragment's own distance to `light_pos` against whichever face it falls
+/// in, instead of a projected, face-relative depth value.
+pub struct CubeShadowMap {
+    pub faces: Vec<RenderTexture>,
+    pub light_pos: Point3<f32>,
+    pub far_plane: f32,
+    pub settings: ShadowSettings,
+}
+
+impl CubeShadowMap {
+    pub fn new(gl: &WebGLRenderingContext, settings: ShadowSettings) -> CubeShadowMap {
+        let faces = (0..6)
+            .map(|_| RenderTexture::new_depth(gl, settings.resolution, settings.resolution))
+            .collect();
+
+        CubeShadowMap {
+            faces,
+            light_pos: Point3::new(0.0, 0.0, 0.0),
+            far_plane: 25.0,
+            settings,
+        }
+    }
+
+    /// 90-degree-FOV view-projection for face `i`, looking out from
+    /// `light_pos` along that face's axis; 6 of these exactly tile the
+    /// sphere around the light.
+    pub fn face_view_proj(&self, i: usize) -> Matrix4<f32> {
+        let (dir, up) = cube_face_directions()[i];
+        let proj = Matrix4::new_perspective(1.0, ::std::f32::consts::FRAC_PI_2, 0.05, self.far_plane);
+        let view = Matrix4::look_at_rh(&self.light_pos, &(self.light_pos + dir), &up);
+
+        proj * view
+    }
+}
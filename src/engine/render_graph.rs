@@ -0,0 +1,149 @@
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use engine::render::{Camera, Material, RenderQueue, RenderTexture};
+
+/// One node in the render graph: either scene geometry rendered into a
+/// target, or a full-screen pass that reads prior outputs and writes one.
+pub enum RenderGraphNode {
+    /// `render_pass_with_material` scoped to a single `RenderQueue`,
+    /// drawing into `output` (or the screen, when `output` is `None`).
+    Scene {
+        name: &'static str,
+        /// `None` renders every queue, matching plain `render_pass`.
+        queue: Option<RenderQueue>,
+        output: Option<Rc<RenderTexture>>,
+    },
+    /// Binds `inputs` onto `material` and draws a single full-screen
+    /// triangle into `output` (or the screen).
+    FullscreenQuad {
+        name: &'static str,
+        inputs: Vec<(&'static str, &'static str)>,
+        material: Rc<Material>,
+        output: Option<Rc<RenderTexture>>,
+    },
+}
+
+impl RenderGraphNode {
+    fn name(&self) -> &'static str {
+        match *self {
+            RenderGraphNode::Scene { name, .. } => name,
+            RenderGraphNode::FullscreenQuad { name, .. } => name,
+        }
+    }
+
+    fn inputs(&self) -> Vec<&'static str> {
+        match *self {
+            RenderGraphNode::Scene { .. } => Vec::new(),
+            RenderGraphNode::FullscreenQuad { ref inputs, .. } => {
+                inputs.iter().map(|&(_, src)| src).collect()
+            }
+        }
+    }
+}
+
+/// A user-declared sequence of render passes. Nodes name their inputs by the
+/// producing node's name, so the graph can be topologically ordered before
+/// `Engine::render_graph` wires outputs to inputs and runs each pass in turn.
+#[derive(Default)]
+pub struct RenderGraph {
+    nodes: Vec<RenderGraphNode>,
+}
+
+impl RenderGraph {
+    pub fn new() -> RenderGraph {
+        RenderGraph { nodes: Vec::new() }
+    }
+
+    pub fn add_node(&mut self, node: RenderGraphNode) -> &mut Self {
+        self.nodes.push(node);
+        self
+    }
+
+    pub fn nodes(&self) -> &[RenderGraphNode] {
+        &self.nodes
+    }
+
+    /// The `RenderTexture` a node with the given name renders into, used to
+    /// wire a `FullscreenQuad` node's named inputs to their producer.
+    pub fn output_of(&self, name: &str) -> Option<&Rc<RenderTexture>> {
+        self.nodes.iter().find(|n| n.name() == name).and_then(|n| match *n {
+            RenderGraphNode::Scene { ref output, .. } => output.as_ref(),
+            RenderGraphNode::FullscreenQuad { ref output, .. } => output.as_ref(),
+        })
+    }
+
+    /// Kahn's algorithm over the name → produces/consumes relation; a node
+    /// with no matching producer is assumed to read an externally-supplied
+    /// texture (e.g. the previous frame, or a loaded asset) and has no edge.
+    pub fn topo_sorted(&self) -> Vec<usize> {
+        let n = self.nodes.len();
+        let mut in_degree = vec![0usize; n];
+        let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); n];
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            for input in node.inputs() {
+                if let Some(producer) = self.nodes.iter().position(|n| n.name() == input) {
+                    dependents[producer].push(i);
+                    in_degree[i] += 1;
+                }
+            }
+        }
+
+        let mut queue: VecDeque<usize> = (0..n).filter(|&i| in_degree[i] == 0).collect();
+        let mut order = Vec::with_capacity(n);
+
+        while let Some(i) = queue.pop_front() {
+            order.push(i);
+            for &dep in dependents[i].iter() {
+                in_degree[dep] -= 1;
+                if in_degree[dep] == 0 {
+                    queue.push_back(dep);
+                }
+            }
+        }
+
+        assert_eq!(order.len(), n, "render graph has a cycle");
+        order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scene_reading(name: &'static str) -> RenderGraphNode {
+        RenderGraphNode::Scene {
+            name,
+            queue: None,
+            output: None,
+        }
+    }
+
+    // Scene nodes never declare inputs, so a graph of only Scene nodes has
+    // no edges at all and every node starts (and stays) at in_degree 0.
+    // That's still enough to pin down the FIFO-vs-LIFO regression: a plain
+    // Vec used as a stack (pop from the back) would emit these in reverse
+    // declaration order the moment nothing else pushes onto it, which is
+    // exactly what a VecDeque popped front-to-back must not do.
+    #[test]
+    fn independent_nodes_are_emitted_in_declaration_order() {
+        let mut graph = RenderGraph::new();
+        graph.add_node(scene_reading("shadow"));
+        graph.add_node(scene_reading("opaque"));
+        graph.add_node(scene_reading("ui"));
+        graph.add_node(scene_reading("transparent"));
+
+        assert_eq!(graph.topo_sorted(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn output_of_is_none_for_a_screen_target_or_an_unknown_name() {
+        let mut graph = RenderGraph::new();
+        graph.add_node(scene_reading("shadow"));
+        graph.add_node(scene_reading("opaque"));
+
+        assert!(graph.output_of("opaque").is_none());
+        assert!(graph.output_of("missing").is_none());
+    }
+}
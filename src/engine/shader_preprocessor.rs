@@ -0,0 +1,208 @@
+use std::collections::{BTreeSet, HashSet};
+
+use engine::asset::{AssetResult, AssetSystem};
+
+/// A single `#define`, either a bare flag (`SHADOWS`) or a valued one
+/// (`POINT_LIGHT_COUNT=8`), supplied by the caller compiling a shader
+/// variant.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Define {
+    Flag(String),
+    Value(String, String),
+}
+
+impl Define {
+    fn name(&self) -> &str {
+        match *self {
+            Define::Flag(ref name) => name,
+            Define::Value(ref name, _) => name,
+        }
+    }
+
+    fn as_directive(&self) -> String {
+        match *self {
+            Define::Flag(ref name) => format!("#define {}\n", name),
+            Define::Value(ref name, ref value) => format!("#define {} {}\n", name, value),
+        }
+    }
+}
+
+/// Cache key for a compiled shader variant: the shared source path plus the
+/// sorted set of defines that selected its behavior.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ShaderVariantKey {
+    pub path: String,
+    pub defines: BTreeSet<String>,
+}
+
+impl ShaderVariantKey {
+    pub fn new(path: &str, defines: &[Define]) -> ShaderVariantKey {
+        ShaderVariantKey {
+            path: path.into(),
+            defines: defines.iter().map(Define::as_directive).collect(),
+        }
+    }
+}
+
+/// Resolve `#include "path"` against the asset system (cycle-safe, each
+/// file pasted at most once), then expand `#define`/`#ifdef` blocks against
+/// the caller-supplied defines. Returns final GLSL ready to compile.
+pub fn preprocess<A: AssetSystem>(
+    asset_system: &A,
+    path: &str,
+    defines: &[Define],
+) -> AssetResult<String> {
+    let mut visited = HashSet::new();
+    let mut out = String::new();
+
+    for define in defines {
+        out.push_str(&define.as_directive());
+    }
+
+    resolve_includes(asset_system, path, &mut visited, &mut out)?;
+
+    let active: HashSet<&str> = defines.iter().map(Define::name).collect();
+    Ok(expand_conditionals(&out, &active))
+}
+
+fn resolve_includes<A: AssetSystem>(
+    asset_system: &A,
+    path: &str,
+    visited: &mut HashSet<String>,
+    out: &mut String,
+) -> AssetResult<()> {
+    if !visited.insert(path.into()) {
+        // Already pasted once (diamond include or cycle) - skip silently.
+        return Ok(());
+    }
+
+    let source = asset_system.load_text(path)?;
+
+    for line in source.lines() {
+        let trimmed = line.trim_start();
+
+        if trimmed.starts_with("#include") {
+            let included = trimmed
+                .trim_start_matches("#include")
+                .trim()
+                .trim_matches('"');
+
+            let resolved = resolve_include_path(path, included);
+            resolve_includes(asset_system, &resolved, visited, out)?;
+        } else {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+
+    Ok(())
+}
+
+fn resolve_include_path(from: &str, include: &str) -> String {
+    if include.starts_with('/') {
+        return include.trim_start_matches('/').into();
+    }
+
+    match from.rfind('/') {
+        Some(idx) => format!("{}/{}", &from[..idx], include),
+        None => include.into(),
+    }
+}
+
+/// A tiny `#ifdef`/`#ifndef`/`#else`/`#endif` expander. Nested blocks are
+/// supported; anything else (including `#define`) passes through untouched
+/// so the GLSL compiler's own preprocessor still sees it.
+fn expand_conditionals(source: &str, active: &HashSet<&str>) -> String {
+    let mut out = String::new();
+    let mut stack: Vec<bool> = vec![true];
+
+    for line in source.lines() {
+        let trimmed = line.trim_start();
+
+        if trimmed.starts_with("#ifdef") {
+            let name = trimmed.trim_start_matches("#ifdef").trim();
+            let parent = *stack.last().unwrap();
+            stack.push(parent && active.contains(name));
+            continue;
+        }
+
+        if trimmed.starts_with("#ifndef") {
+            let name = trimmed.trim_start_matches("#ifndef").trim();
+            let parent = *stack.last().unwrap();
+            stack.push(parent && !active.contains(name));
+            continue;
+        }
+
+        if trimmed.starts_with("#else") {
+            let cond = stack.pop().unwrap_or(true);
+            let parent = *stack.last().unwrap_or(&true);
+            stack.push(parent && !cond);
+            continue;
+        }
+
+        if trimmed.starts_with("#endif") {
+            stack.pop();
+            continue;
+        }
+
+        if stack.iter().all(|&b| b) {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ifdef_keeps_the_active_branch() {
+        let active: HashSet<&str> = ["SHADOWS"].iter().cloned().collect();
+        let source = "a\n#ifdef SHADOWS\nb\n#else\nc\n#endif\nd\n";
+
+        assert_eq!(expand_conditionals(source, &active), "a\nb\nd\n");
+    }
+
+    #[test]
+    fn ifdef_without_the_define_takes_the_else_branch() {
+        let active: HashSet<&str> = HashSet::new();
+        let source = "a\n#ifdef SHADOWS\nb\n#else\nc\n#endif\nd\n";
+
+        assert_eq!(expand_conditionals(source, &active), "a\nc\nd\n");
+    }
+
+    #[test]
+    fn ifndef_is_the_inverse_of_ifdef() {
+        let active: HashSet<&str> = ["SHADOWS"].iter().cloned().collect();
+        let source = "#ifndef SHADOWS\nb\n#endif\n";
+
+        assert_eq!(expand_conditionals(source, &active), "");
+    }
+
+    #[test]
+    fn nested_blocks_require_every_enclosing_condition() {
+        let active: HashSet<&str> = ["OUTER"].iter().cloned().collect();
+        let source = "#ifdef OUTER\n#ifdef INNER\nboth\n#endif\nouter_only\n#endif\n";
+
+        assert_eq!(expand_conditionals(source, &active), "outer_only\n");
+    }
+
+    #[test]
+    fn resolve_include_path_is_relative_to_the_including_file() {
+        assert_eq!(
+            resolve_include_path("shaders/lighting.glsl", "common.glsl"),
+            "shaders/common.glsl"
+        );
+    }
+
+    #[test]
+    fn resolve_include_path_absolute_strips_the_leading_slash() {
+        assert_eq!(
+            resolve_include_path("shaders/lighting.glsl", "/shaders/common.glsl"),
+            "shaders/common.glsl"
+        );
+    }
+}